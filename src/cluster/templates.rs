@@ -178,3 +178,19 @@ impl<'a> ResourcequotaTemplate<'a> {
         .render()
     }
 }
+
+#[derive(Template)]
+#[template(path = "cluster/storage-class.yml")]
+pub struct StorageClassTemplate<'a> {
+    name: &'a str,
+}
+
+impl<'a> StorageClassTemplate<'a> {
+    pub fn instantiate(class: &StorageClass) -> Resourcefile {
+        (Resourceproto {
+            name: &format!("storage-class-{}.yml", class.name()),
+            prototype: Self { name: class.name() },
+        })
+        .render()
+    }
+}
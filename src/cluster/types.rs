@@ -104,8 +104,32 @@ pub struct User {
     pub permissions: Option<HashMap<String, Vec<ClusterRole>>>,
 }
 
+/// A StorageClass provisioning Google Compute Engine persistent disks, named after their type.
+/// Generated by `cluster-generate` and enabled by projects in their `options`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum StorageClass {
+    #[serde(rename = "pd-ssd")]
+    PdSsd,
+    #[serde(rename = "pd-hdd")]
+    PdHdd,
+}
+
+impl StorageClass {
+    pub const ALL: [StorageClass; 2] = [StorageClass::PdSsd, StorageClass::PdHdd];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StorageClass::PdSsd => "pd-ssd",
+            StorageClass::PdHdd => "pd-hdd",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClusterManifest {
     pub namespaces: Vec<Namespace>,
     pub users: Vec<User>,
+    /// Storage classes shared by the projects, which enable them in their `options`.
+    #[serde(default)]
+    pub storage_classes: Vec<StorageClass>,
 }
@@ -15,6 +15,10 @@ pub fn exec(path: &std::path::Path, destination: &std::path::Path) {
         apply_files.push(NamespaceTemplate::instantiate(&namespace));
     }
 
+    for class in &manifest.storage_classes {
+        apply_files.push(StorageClassTemplate::instantiate(class));
+    }
+
     let mut auth_files = vec![];
     for user in manifest.users {
         if user.kind == UserKind::ServiceAccount {
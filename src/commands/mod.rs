@@ -1,10 +1,12 @@
 pub mod cluster_generate;
 pub mod project_deploy;
+pub mod project_diff;
 pub mod project_generate;
+pub mod secret;
 
 use crate::error::{ErrorKind, Result};
-use crate::kubectl::Kubectl;
-use crate::project::types::Manifest;
+use crate::kubernetes::Client;
+use crate::project::types::{EncryptionType, Manifest};
 use crate::resourcefile::Resourcefile;
 use failure::ResultExt;
 
@@ -25,6 +27,49 @@ impl ProjectOpts {
     }
 }
 
+pub(crate) const DEPLOY_TOKEN_NAME: &str = "DEPLOY_TOKEN";
+
+/// How to reach and authenticate to the cluster.
+#[derive(Debug)]
+pub struct ClusterOpts {
+    kubeconfig: Option<std::path::PathBuf>,
+    token_source: Option<(EncryptionType, std::path::PathBuf)>,
+}
+
+impl ClusterOpts {
+    pub fn parse(opts: &clap::ArgMatches) -> ClusterOpts {
+        let token = clap::value_t!(opts.value_of("token"), std::path::PathBuf);
+        let token_type = clap::value_t!(opts.value_of("token-type"), EncryptionType);
+
+        ClusterOpts {
+            kubeconfig: clap::value_t!(opts.value_of("kubeconfig"), std::path::PathBuf).ok(),
+            token_source: match (token_type, token) {
+                (Ok(e), Ok(token)) => Some((e, token)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Connect to the cluster, using the encrypted token file or `DEPLOY_TOKEN` if given.
+    pub fn connect(&self, namespace: &str) -> Result<Client> {
+        let secret = crate::util::get_secret();
+        let secret = secret.as_deref();
+
+        let token: Option<String> = match &self.token_source {
+            Some((encryption, path)) => Some(
+                String::from_utf8(
+                    crate::secrets::read_secret_from_file(*encryption, path, secret)
+                        .context(ErrorKind::TokenError)?,
+                )
+                .context(ErrorKind::TokenError)?,
+            ),
+            None => std::env::var(DEPLOY_TOKEN_NAME).ok(),
+        };
+
+        Client::new(self.kubeconfig.clone(), token, Some(namespace.to_owned()))
+    }
+}
+
 fn load_inventory(path: &std::path::Path) -> Result<Vec<yaml_rust::Yaml>> {
     let content = std::fs::read_to_string(path).context(ErrorKind::FileReadError {
         name: path.to_owned(),
@@ -39,17 +84,43 @@ fn load_inventory(path: &std::path::Path) -> Result<Vec<yaml_rust::Yaml>> {
 fn load_manifest_with_context(path: &std::path::Path, context: &tera::Context) -> Result<Manifest> {
     let content: std::string::String = crate::util::process_template(path, context)?;
 
-    let manifest: Manifest = serde_yaml::from_str(&content).context(ErrorKind::ParseError {
+    let mut manifest: Manifest = serde_yaml::from_str(&content).context(ErrorKind::ParseError {
         name: path.to_owned(),
     })?;
+    manifest.set_identity(path);
 
     Ok(manifest)
 }
 
+/// The environment variables required by the manifest, read from the manifest before it is
+/// rendered, such that they can be used in the manifest itself, e.g. for image tags.
+fn required_env(path: &std::path::Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path).context(ErrorKind::FileReadError {
+        name: path.to_owned(),
+    })?;
+
+    let documents =
+        yaml_rust::YamlLoader::load_from_str(&content).context(ErrorKind::ParseError {
+            name: path.to_owned(),
+        })?;
+
+    Ok(documents
+        .first()
+        .and_then(|d| d["env"].as_vec())
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|n| n.as_str())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
 pub type LoadedManifest<'a> = (Manifest, tera::Context);
 
 pub fn load_manifest<'a>(opts: &ProjectOpts) -> Result<LoadedManifest<'a>> {
-    let context = match &opts.inventory {
+    let mut context = match &opts.inventory {
         Some(inventory) => {
             let inventory = load_inventory(inventory).context(ErrorKind::InventoryError)?;
             crate::util::map_yaml_to_context(inventory).context(ErrorKind::InventoryError)?
@@ -57,8 +128,39 @@ pub fn load_manifest<'a>(opts: &ProjectOpts) -> Result<LoadedManifest<'a>> {
         None => tera::Context::new(),
     };
 
-    let manifest =
-        load_manifest_with_context(&opts.manifest, &context).context(ErrorKind::ManifestError)?;
+    // The variables are only required when the manifest is rendered, see `to_sourcefiles`.
+    let required = required_env(&opts.manifest);
+    for name in required.iter().flatten() {
+        if let Ok(value) = std::env::var(name) {
+            context.insert(name, &value);
+        }
+    }
+
+    let manifest = match load_manifest_with_context(&opts.manifest, &context) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            use failure::Fail;
+
+            // A missing variable or a manifest that is not YAML before rendering would otherwise
+            // only show up as an unknown variable in the template.
+            match &required {
+                Ok(names) => {
+                    if let Some(name) = names.iter().find(|n| std::env::var(n).is_err()) {
+                        return Err(e.context(ErrorKind::EnvError { name: name.clone() }).into());
+                    }
+                }
+                Err(parse_error) => {
+                    let message = parse_error.message();
+                    return Err(e
+                        .context(ErrorKind::EnvReadError { message })
+                        .context(ErrorKind::ManifestError)
+                        .into());
+                }
+            }
+
+            return Err(e.context(ErrorKind::ManifestError).into());
+        }
+    };
 
     Ok((manifest, context))
 }
@@ -66,15 +168,63 @@ pub fn load_manifest<'a>(opts: &ProjectOpts) -> Result<LoadedManifest<'a>> {
 pub fn compute_project_files(
     opts: &ProjectOpts,
     (manifest, context): &LoadedManifest<'_>,
-    kubectl: Option<&Kubectl>,
 ) -> Result<Vec<Resourcefile>> {
     let base = opts.manifest.parent().unwrap().to_owned();
     let secret = crate::util::get_secret();
     let secret = secret.as_deref();
 
     let files = manifest
-        .to_sourcefiles(&base, context.clone(), secret, kubectl)
+        .to_sourcefiles(&base, context.clone(), secret)
         .context(ErrorKind::ManifestError)?;
 
     Ok(files)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(content: &str) -> Result<LoadedManifest<'static>> {
+        let directory = tempfile::tempdir().unwrap();
+        let manifest = directory.path().join("manifest.yml");
+        std::fs::write(&manifest, content).unwrap();
+
+        load_manifest(&ProjectOpts {
+            manifest,
+            inventory: None,
+            dry_run: false,
+        })
+    }
+
+    #[test]
+    fn reports_the_missing_env() {
+        let error = load(
+            "version: 1\nnamespace: test\nenv: [KUBERWAVE_TEST_UNSET]\n\
+             image: \"app:{{ KUBERWAVE_TEST_UNSET }}\"\n",
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::EnvError {
+                name: "KUBERWAVE_TEST_UNSET".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn reports_why_the_env_could_not_be_read() {
+        // Only YAML after rendering, and rendering fails on the unknown variable.
+        let error =
+            load("{% if true %}version: 1{% endif %}\nnamespace: {{ namespace }}\n").unwrap_err();
+
+        assert_eq!(error.kind(), &ErrorKind::ManifestError);
+        let message = error.message();
+        assert!(
+            message.contains("The env of the manifest could not be read before rendering it"),
+            "{}",
+            message
+        );
+        assert!(message.contains("manifest.yml"), "{}", message);
+    }
+}
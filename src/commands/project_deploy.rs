@@ -1,55 +1,481 @@
-use std::env;
-
-use crate::commands::{compute_project_files, load_manifest, ProjectOpts};
-use crate::error::{ErrorKind, Result};
-use crate::project::types::EncryptionType;
-use crate::util;
-
-pub(crate) const DEPLOY_TOKEN_NAME: &str = "DEPLOY_TOKEN"; 
-
-pub fn exec(
-    opts: ProjectOpts,
-    kubeconfig: Option<std::path::PathBuf>,
-    token_source: Option<(EncryptionType, std::path::PathBuf)>,
-) -> Result<()> {
-    use failure::ResultExt;
-
-    let secret = util::get_secret();
-    let secret = secret.as_deref();
-
-    let token: Option<String> = match token_source {
-        Some((encryption, path)) => Some(
-            String::from_utf8(
-                crate::secrets::read_secret_from_file(encryption, &path, secret)
-                    .context(ErrorKind::TokenError)?,
-            )
-            .context(ErrorKind::TokenError)?,
-        ),
-        None => {
-            if let Some(token) = env::var(DEPLOY_TOKEN_NAME).ok() {
-                Some(token)
-            } else {
-                None
+use crate::commands::{compute_project_files, load_manifest, ClusterOpts, ProjectOpts};
+use crate::error::{Error, ErrorKind, Result};
+use crate::kubernetes::{rollout, Client, ObjectRef};
+use crate::project::types::Manifest;
+use crate::resourcefile::Resourcefile;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// Resource types that are checked for leftovers when pruning, in addition to the types that are
+/// currently generated. Every namespaced kind a manifest can generate is listed; the kinds of
+/// custom resources that the cluster does not know are skipped.
+const PRUNE_TYPES: &[(&str, &str)] = &[
+    ("v1", "ConfigMap"),
+    ("v1", "Secret"),
+    ("v1", "Service"),
+    ("v1", "PersistentVolumeClaim"),
+    ("apps/v1", "Deployment"),
+    ("apps/v1", "StatefulSet"),
+    ("apps/v1", "DaemonSet"),
+    ("batch/v1", "Job"),
+    ("batch/v1", "CronJob"),
+    ("networking.k8s.io/v1", "Ingress"),
+    ("networking.k8s.io/v1", "NetworkPolicy"),
+    ("cert-manager.io/v1", "Certificate"),
+    ("gateway.networking.k8s.io/v1", "Gateway"),
+    ("gateway.networking.k8s.io/v1", "HTTPRoute"),
+    ("gateway.networking.k8s.io/v1beta1", "ReferenceGrant"),
+    ("traefik.io/v1alpha1", "Middleware"),
+];
+
+/// The API version and kind of an object.
+type ObjectType = (String, String);
+
+/// The API group, kind and name of an object.
+type Identity = (String, String, String);
+
+/// Objects are compared by API group rather than version, so a version bump does not prune them.
+fn identity(object: &ObjectRef) -> Identity {
+    let group = object
+        .api_version
+        .rsplit_once('/')
+        .map(|(group, _)| group)
+        .unwrap_or("");
+
+    (group.to_owned(), object.kind.clone(), object.name.clone())
+}
+
+/// The types to look for leftovers of, and the identities of the generated objects.
+fn generated_objects(files: &[Resourcefile]) -> Result<(Vec<ObjectType>, HashSet<Identity>)> {
+    let mut generated = HashSet::new();
+    let mut types: Vec<ObjectType> = PRUNE_TYPES
+        .iter()
+        .map(|(api_version, kind)| (api_version.to_string(), kind.to_string()))
+        .collect();
+
+    for file in files {
+        for object in file.objects()? {
+            let object = ObjectRef::from_object(&object).ok_or_else(|| ErrorKind::ObjectError {
+                name: file.name.clone(),
+            })?;
+            let object_type = (object.api_version.clone(), object.kind.clone());
+            if !types.contains(&object_type) {
+                types.push(object_type);
+            }
+            generated.insert(identity(&object));
+        }
+    }
+
+    Ok((types, generated))
+}
+
+/// Select the live objects labelled as owned by this manifest that are no longer generated.
+fn select_prunable(
+    manifest: &Manifest,
+    generated: &HashSet<Identity>,
+    live: &[serde_json::Value],
+) -> Vec<ObjectRef> {
+    let labels = manifest.ownership_labels();
+    let mut prunable = vec![];
+
+    for object in live {
+        let metadata = &object["metadata"];
+        if !labels
+            .iter()
+            .all(|(key, value)| metadata["labels"][*key] == *value)
+        {
+            continue;
+        }
+
+        // Leave objects created by a controller to that controller.
+        if metadata["ownerReferences"]
+            .as_array()
+            .is_some_and(|o| !o.is_empty())
+        {
+            continue;
+        }
+
+        if let Some(object) = ObjectRef::from_object(object) {
+            if !generated.contains(&identity(&object)) {
+                prunable.push(object);
+            }
+        }
+    }
+
+    prunable
+}
+
+/// Find the objects labelled as owned by this manifest that are no longer generated.
+fn find_prunable(
+    client: &Client,
+    manifest: &Manifest,
+    files: &[Resourcefile],
+) -> Result<Vec<ObjectRef>> {
+    let (types, generated) = generated_objects(files)?;
+
+    let selector = manifest.ownership_selector();
+    let mut live = vec![];
+    for (api_version, kind) in types {
+        match client.list(&api_version, &kind, &selector) {
+            Ok(objects) => live.extend(objects),
+            Err(e) if matches!(e.kind(), ErrorKind::UnknownResourceError { .. }) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(select_prunable(manifest, &generated, &live))
+}
+
+#[derive(Debug)]
+pub struct DeployOpts {
+    prune: bool,
+    server_dry_run: bool,
+    wait: bool,
+    timeout: Duration,
+    run_jobs: Vec<String>,
+}
+
+impl DeployOpts {
+    pub fn parse(opts: &clap::ArgMatches) -> DeployOpts {
+        DeployOpts {
+            prune: opts.is_present("prune"),
+            server_dry_run: opts.is_present("server-dry-run"),
+            wait: opts.is_present("wait"),
+            timeout: Duration::from_secs(clap::value_t_or_exit!(opts.value_of("timeout"), u64)),
+            run_jobs: opts
+                .values_of("run-job")
+                .map(|jobs| jobs.map(str::to_owned).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// A Job to run before the other files are applied, with the file it was generated in.
+type JobFile = (ObjectRef, Resourcefile);
+
+/// Split off the files containing the Jobs that have to complete before the rest is applied.
+fn take_jobs(
+    files: Vec<Resourcefile>,
+    names: &[String],
+) -> Result<(Vec<JobFile>, Vec<Resourcefile>)> {
+    let mut jobs = vec![];
+    let mut rest = vec![];
+
+    for file in files {
+        let job = file
+            .objects()?
+            .iter()
+            .filter_map(ObjectRef::from_object)
+            .find(|o| o.kind == "Job" && names.contains(&o.name));
+
+        match job {
+            Some(job) => jobs.push((job, file)),
+            None => rest.push(file),
+        }
+    }
+
+    for name in names {
+        if !jobs.iter().any(|(job, _)| &job.name == name) {
+            return Err(Error::create(
+                format!("Unknown job '{}'", name),
+                ErrorKind::ManifestError,
+            ));
+        }
+    }
+
+    Ok((jobs, rest))
+}
+
+/// Whether an object is a Job that is not run with `--run-job`. Applying a Job again fails as
+/// its pod template is immutable, and would re-run it if it was already cleaned up.
+fn is_skipped_job(object: &serde_json::Value, names: &[String]) -> bool {
+    object["kind"] == "Job"
+        && object["apiVersion"]
+            .as_str()
+            .is_some_and(|v| v.starts_with("batch/"))
+        && !object["metadata"]["name"]
+            .as_str()
+            .is_some_and(|name| names.iter().any(|n| n == name))
+}
+
+/// Apply every object in a file, except the Jobs that are not run.
+fn apply(client: &Client, file: &Resourcefile, names: &[String]) -> Result<()> {
+    for object in file.objects()? {
+        if is_skipped_job(&object, names) {
+            if let Some(job) = ObjectRef::from_object(&object) {
+                println!("Skipping {}, it is only run with --run-job", job);
+            }
+            continue;
+        }
+
+        client.apply_object(&object)?;
+    }
+
+    Ok(())
+}
+
+fn has_workload(file: &Resourcefile) -> Result<bool> {
+    Ok(file.objects()?.iter().any(|object| {
+        object["kind"] == "CronJob"
+            || object["kind"]
+                .as_str()
+                .is_some_and(|kind| rollout::WORKLOAD_KINDS.contains(&kind))
+    }))
+}
+
+/// Run a Job to completion, replacing a previous run as the pod template of a Job is immutable.
+fn run_job(client: &Client, job: &ObjectRef, file: &Resourcefile, timeout: Duration) -> Result<()> {
+    if client.get(job)?.is_some() {
+        println!("Deleting previous {}", job);
+        client.delete(job)?;
+
+        let deadline = Instant::now() + timeout;
+        while client.get(job)?.is_some() {
+            if Instant::now() >= deadline {
+                return Err(Error::create(
+                    format!("previous {} was not deleted in time", job),
+                    ErrorKind::RolloutError {
+                        name: job.to_string(),
+                        diagnostics: vec![],
+                    },
+                ));
             }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    println!("Running {}", job);
+    client.apply(file)?;
+    rollout::wait(client, std::slice::from_ref(job), timeout)
+}
+
+/// Submit every file with a server-side dry-run, reporting all rejected files at once.
+fn validate(client: &Client, files: &[Resourcefile], names: &[String]) -> Result<()> {
+    let mut rejected = vec![];
+    for file in files {
+        let result = file.objects().and_then(|objects| {
+            objects
+                .iter()
+                .filter(|object| !is_skipped_job(object, names))
+                .try_for_each(|object| client.apply_object_dry_run(object).map(|_| ()))
+        });
+        if let Err(e) = result {
+            rejected.push((file.name.clone(), e.message()));
         }
-    };
+    }
 
+    if !rejected.is_empty() {
+        return Err(ErrorKind::DryRunError { rejected }.into());
+    }
+
+    Ok(())
+}
+
+pub fn exec(opts: ProjectOpts, cluster: ClusterOpts, deploy: DeployOpts) -> Result<()> {
     let loaded_manifest = load_manifest(&opts)?;
     let (manifest, _) = &loaded_manifest;
 
-    let kubectl = crate::kubectl::Kubectl::new(kubeconfig, token, Some(manifest.namespace.clone()));
+    let client = cluster.connect(&manifest.namespace)?;
+
+    let mut files = compute_project_files(&opts, &loaded_manifest)?;
+
+    let dry_run = opts.dry_run || deploy.server_dry_run;
 
-    let files = compute_project_files(&opts, &loaded_manifest, Some(&kubectl))?;
+    if deploy.server_dry_run {
+        validate(&client, &files, &deploy.run_jobs)?;
 
-    if opts.dry_run {
+        println!("{} files validated by the cluster (dry-run).", files.len());
+    } else if opts.dry_run {
         println!("{} files generated (dry-run).", files.len());
     } else {
+        let (jobs, rest) = take_jobs(files, &deploy.run_jobs)?;
+
+        // The jobs may depend on ConfigMaps and Secrets, but the workloads have to wait for them.
+        let mut workloads = vec![];
+        for file in &rest {
+            if jobs.is_empty() || !has_workload(file)? {
+                apply(&client, file, &deploy.run_jobs)?;
+            } else {
+                workloads.push(file);
+            }
+        }
+
+        for (job, file) in &jobs {
+            run_job(&client, job, file, deploy.timeout)?;
+        }
+
+        for file in workloads {
+            apply(&client, file, &deploy.run_jobs)?;
+        }
+
+        println!("{} files deployed.", jobs.len() + rest.len());
+
+        files = jobs.into_iter().map(|(_, file)| file).chain(rest).collect();
+    }
+
+    if deploy.wait {
+        let timeout = deploy.timeout;
+        let mut workloads = vec![];
         for file in &files {
-            kubectl.apply(file)?;
+            for object in file.objects()? {
+                // Jobs that were run have completed already, others were not applied.
+                match ObjectRef::from_object(&object) {
+                    Some(object)
+                        if object.kind != "Job"
+                            && rollout::WORKLOAD_KINDS.contains(&object.kind.as_str()) =>
+                    {
+                        workloads.push(object)
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        rollout::wait(&client, &workloads, timeout)?;
+
+        println!("{} workloads ready.", workloads.len());
+    }
+
+    if deploy.prune {
+        let prunable = find_prunable(&client, manifest, &files)?;
+
+        for object in &prunable {
+            if dry_run {
+                println!("Would prune {}", object);
+            } else {
+                println!("Pruning {}", object);
+                client.delete(object)?;
+            }
         }
 
-        println!("{} files deployed.", files.len());
+        if dry_run {
+            println!("{} resources would be pruned (dry-run).", prunable.len());
+        } else {
+            println!("{} resources pruned.", prunable.len());
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn file(name: &str, buffer: &str) -> Resourcefile {
+        Resourcefile {
+            name: name.to_owned(),
+            buffer: buffer.to_owned(),
+            labels: vec![],
+        }
+    }
+
+    fn object(
+        api_version: &str,
+        kind: &str,
+        name: &str,
+        labels: serde_json::Value,
+    ) -> serde_json::Value {
+        json!({
+            "apiVersion": api_version,
+            "kind": kind,
+            "metadata": {"name": name, "labels": labels},
+        })
+    }
+
+    #[test]
+    fn selects_the_leftovers_of_the_manifest() {
+        let mut manifest: Manifest =
+            serde_yaml::from_str("version: 1\nnamespace: test\nproject: web\n").unwrap();
+        manifest.identity = "deploy-production".to_owned();
+
+        let files = [file(
+            "deployment-web.yml",
+            "apiVersion: apps/v1\nkind: Deployment\nmetadata:\n  name: web\n",
+        )];
+        let (types, generated) = generated_objects(&files).unwrap();
+        assert!(types.contains(&("apps/v1".to_owned(), "Deployment".to_owned())));
+
+        let owned = json!({
+            "app.kubernetes.io/managed-by": "kuberwave",
+            "kuberwave/project": "web",
+            "kuberwave/manifest": "deploy-production",
+        });
+        let other_manifest = json!({
+            "app.kubernetes.io/managed-by": "kuberwave",
+            "kuberwave/project": "web",
+            "kuberwave/manifest": "deploy-staging",
+        });
+        let other_project = json!({
+            "app.kubernetes.io/managed-by": "kuberwave",
+            "kuberwave/project": "api",
+            "kuberwave/manifest": "deploy-production",
+        });
+        let mut controlled = object("v1", "Secret", "web-token", owned.clone());
+        controlled["metadata"]["ownerReferences"] = json!([{"kind": "Certificate"}]);
+
+        let live = [
+            // Still generated, also under an older version of its API.
+            object("apps/v1", "Deployment", "web", owned.clone()),
+            object("apps/v1beta2", "Deployment", "web", owned.clone()),
+            object("v1", "ConfigMap", "web-old", owned.clone()),
+            object("v1", "ConfigMap", "web-staging", other_manifest),
+            object("v1", "ConfigMap", "api", other_project),
+            object("v1", "ConfigMap", "unlabelled", json!({})),
+            controlled,
+        ];
+
+        let prunable: Vec<String> = select_prunable(&manifest, &generated, &live)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            prunable,
+            vec![ObjectRef::from_object(&live[2]).unwrap().to_string()]
+        );
+    }
+
+    const JOB: &str = "apiVersion: batch/v1\nkind: Job\nmetadata:\n  name: migrate\n";
+    const CRONJOB: &str = "apiVersion: batch/v1\nkind: CronJob\nmetadata:\n  name: migrate\n";
+
+    fn job_files() -> Vec<Resourcefile> {
+        vec![
+            file("job-migrate.yml", JOB),
+            file("cronjob-migrate.yml", CRONJOB),
+        ]
+    }
+
+    #[test]
+    fn jobs_are_only_run_with_run_job() {
+        let names: Vec<String> = vec![];
+        let (jobs, rest) = take_jobs(job_files(), &names).unwrap();
+        assert!(jobs.is_empty());
+        assert_eq!(rest.len(), 2);
+
+        let objects: Vec<serde_json::Value> =
+            rest.iter().flat_map(|f| f.objects().unwrap()).collect();
+        assert!(is_skipped_job(&objects[0], &names));
+        assert!(!is_skipped_job(&objects[1], &names));
+
+        let names = vec!["migrate".to_owned()];
+        let (jobs, rest) = take_jobs(job_files(), &names).unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].0.kind, "Job");
+        assert_eq!(jobs[0].1.name, "job-migrate.yml");
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].name, "cronjob-migrate.yml");
+
+        let job = &jobs[0].1.objects().unwrap()[0];
+        assert!(!is_skipped_job(job, &names));
+        assert!(!is_skipped_job(&rest[0].objects().unwrap()[0], &names));
+    }
+
+    #[test]
+    fn unknown_jobs_are_rejected() {
+        let error = take_jobs(job_files(), &["seed".to_owned()])
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ManifestError);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::commands::{compute_project_files, load_manifest, ClusterOpts, ProjectOpts};
+use crate::error::{ErrorKind, Result};
+use crate::kubernetes::ObjectRef;
+use failure::ResultExt;
+use serde_json::{Map, Value};
+
+/// Fields the API server maintains itself, which would show up as noise in every diff.
+const SERVER_FIELDS: &[&str] = &[
+    "managedFields",
+    "resourceVersion",
+    "generation",
+    "uid",
+    "creationTimestamp",
+    "selfLink",
+];
+
+fn normalize(object: &mut Value) {
+    if let Some(metadata) = object["metadata"].as_object_mut() {
+        for field in SERVER_FIELDS {
+            metadata.remove(*field);
+        }
+    }
+
+    if let Some(object) = object.as_object_mut() {
+        object.remove("status");
+    }
+}
+
+fn mask(values: Option<&mut Map<String, Value>>, other: &Map<String, Value>, changed: &str) {
+    for (key, value) in values.into_iter().flatten() {
+        let redacted = if other.get(key) == Some(value) {
+            "<redacted>"
+        } else {
+            changed
+        };
+        *value = Value::from(redacted);
+    }
+}
+
+/// Hide Secret values, while still showing which keys were added, removed or changed.
+fn redact(mut live: Option<&mut Value>, desired: &mut Value) {
+    if desired["kind"] != "Secret" {
+        return;
+    }
+
+    for field in &["data", "stringData"] {
+        let before = live
+            .as_deref()
+            .and_then(|l| l[*field].as_object())
+            .cloned()
+            .unwrap_or_default();
+        let after = desired[*field].as_object().cloned().unwrap_or_default();
+
+        mask(
+            live.as_deref_mut().and_then(|l| l[*field].as_object_mut()),
+            &after,
+            "<redacted:before>",
+        );
+        mask(desired[*field].as_object_mut(), &before, "<redacted:after>");
+    }
+}
+
+fn to_yaml(object: &Option<Value>, object_ref: &ObjectRef) -> Result<String> {
+    Ok(match object {
+        Some(object) => serde_yaml::to_string(object).context(ErrorKind::ObjectError {
+            name: object_ref.to_string(),
+        })?,
+        None => String::new(),
+    })
+}
+
+/// Print a unified diff for every generated object that differs from the live cluster.
+///
+/// The desired state is computed with a server-side dry-run apply, so defaults and mutating
+/// webhooks do not show up as differences. Returns whether there were any differences.
+pub fn exec(opts: ProjectOpts, cluster: ClusterOpts) -> Result<bool> {
+    let loaded_manifest = load_manifest(&opts)?;
+    let (manifest, _) = &loaded_manifest;
+
+    let client = cluster.connect(&manifest.namespace)?;
+
+    let files = compute_project_files(&opts, &loaded_manifest)?;
+
+    let mut total = 0;
+    let mut changed = 0;
+    for file in &files {
+        for object in file.objects()? {
+            let object_ref =
+                ObjectRef::from_object(&object).ok_or_else(|| ErrorKind::ObjectError {
+                    name: file.name.clone(),
+                })?;
+            total += 1;
+
+            let mut live = client.get(&object_ref)?;
+            let mut desired = client.apply_object_dry_run(&object)?;
+
+            if let Some(live) = &mut live {
+                normalize(live);
+            }
+            normalize(&mut desired);
+
+            if live.as_ref() == Some(&desired) {
+                continue;
+            }
+
+            redact(live.as_mut(), &mut desired);
+
+            let before = to_yaml(&live, &object_ref)?;
+            let after = to_yaml(&Some(desired), &object_ref)?;
+            let diff = similar::TextDiff::from_lines(&before, &after);
+
+            let live_name = if live.is_some() {
+                format!("live/{}", object_ref)
+            } else {
+                "/dev/null".to_owned()
+            };
+            print!(
+                "{}",
+                diff.unified_diff()
+                    .header(&live_name, &format!("kuberwave/{}", object_ref))
+            );
+
+            changed += 1;
+        }
+    }
+
+    println!("{} of {} resources differ.", changed, total);
+
+    Ok(changed > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn normalize_removes_server_fields() {
+        let mut object = json!({
+            "kind": "ConfigMap",
+            "metadata": {
+                "name": "web",
+                "uid": "1234",
+                "resourceVersion": "42",
+                "managedFields": [],
+                "creationTimestamp": "2020-01-01T00:00:00Z",
+            },
+            "data": {"key": "value"},
+            "status": {},
+        });
+        normalize(&mut object);
+
+        assert_eq!(
+            object,
+            json!({
+                "kind": "ConfigMap",
+                "metadata": {"name": "web"},
+                "data": {"key": "value"},
+            })
+        );
+    }
+
+    #[test]
+    fn redact_hides_secret_values() {
+        let mut live = json!({
+            "kind": "Secret",
+            "data": {"same": "YQ==", "changed": "Yg==", "removed": "Yw=="},
+        });
+        let mut desired = json!({
+            "kind": "Secret",
+            "data": {"same": "YQ==", "changed": "ZA==", "added": "ZQ=="},
+            "stringData": {"plain": "secret"},
+        });
+        redact(Some(&mut live), &mut desired);
+
+        assert_eq!(
+            live["data"],
+            json!({
+                "same": "<redacted>",
+                "changed": "<redacted:before>",
+                "removed": "<redacted:before>",
+            })
+        );
+        assert_eq!(
+            desired["data"],
+            json!({
+                "same": "<redacted>",
+                "changed": "<redacted:after>",
+                "added": "<redacted:after>",
+            })
+        );
+        assert_eq!(desired["stringData"], json!({"plain": "<redacted:after>"}));
+
+        let mut config_map = json!({"kind": "ConfigMap", "data": {"key": "value"}});
+        redact(None, &mut config_map);
+        assert_eq!(config_map["data"]["key"], "value");
+    }
+}
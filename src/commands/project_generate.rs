@@ -4,9 +4,9 @@ use crate::error::Result;
 pub fn exec(opts: &ProjectOpts, output: &std::path::Path) -> Result<()> {
     let loaded_manifest = load_manifest(opts)?;
 
-    // Assume we do not have access to kubectl in the generate setting; potentially breaking the
+    // Assume we do not have access to the cluster in the generate setting; potentially breaking the
     // generate and kubectl apply -f scenario.
-    let files = compute_project_files(opts, &loaded_manifest, None)?;
+    let files = compute_project_files(opts, &loaded_manifest)?;
 
     if opts.dry_run {
         println!("{} files generated (dry-run).", files.len());
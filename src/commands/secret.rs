@@ -0,0 +1,298 @@
+use crate::commands::{load_manifest, ProjectOpts};
+use crate::error::{Error, ErrorKind, Result};
+use crate::project::types::EncryptionType;
+use crate::secrets::{encrypt_secret, read_secret_from_file, sops};
+use failure::ResultExt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The ansible-vault password secrets are encrypted with when rekeying.
+pub(crate) const NEW_SECRET_NAME: &str = "NEW_SECRET";
+
+const DEFAULT_EDITOR: &str = "vi";
+
+#[derive(Debug)]
+pub struct SecretOpts {
+    encryption: EncryptionType,
+    /// The age recipients to encrypt SOPS files for.
+    recipients: Vec<String>,
+}
+
+impl SecretOpts {
+    pub fn parse(opts: &clap::ArgMatches) -> SecretOpts {
+        SecretOpts {
+            encryption: clap::value_t_or_exit!(opts.value_of("encryption"), EncryptionType),
+            recipients: opts
+                .values_of("age")
+                .map(|recipients| recipients.map(str::to_owned).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The files given on the command line, all with the encryption type of the options.
+    pub fn files(&self, opts: &clap::ArgMatches) -> Vec<(EncryptionType, PathBuf)> {
+        opts.values_of("file")
+            .into_iter()
+            .flatten()
+            .map(|file| (self.encryption, PathBuf::from(file)))
+            .collect()
+    }
+}
+
+fn read(file: &Path) -> Result<Vec<u8>> {
+    Ok(std::fs::read(file).context(ErrorKind::FileReadError {
+        name: file.to_owned(),
+    })?)
+}
+
+fn write(file: &Path, content: &[u8]) -> Result<()> {
+    Ok(
+        std::fs::write(file, content).context(ErrorKind::FileWriteError {
+            name: file.to_owned(),
+        })?,
+    )
+}
+
+fn is_encrypted(encryption: EncryptionType, file: &Path, content: &[u8]) -> bool {
+    match encryption {
+        EncryptionType::AnsibleVault => content.starts_with(b"$ANSIBLE_VAULT"),
+        EncryptionType::SOPS => sops::is_encrypted(file, content),
+    }
+}
+
+/// The age recipients for a new SOPS file: the given ones, or else those of the available keys.
+fn new_recipients(opts: &SecretOpts, secret: Option<&str>) -> Result<Vec<String>> {
+    if !opts.recipients.is_empty() {
+        return Ok(opts.recipients.clone());
+    }
+
+    Ok(sops::identities(secret)?
+        .iter()
+        .map(|identity| identity.to_public().to_string())
+        .collect())
+}
+
+/// The age recipients to re-encrypt an existing SOPS file for: the given ones, or else the
+/// recipients it is encrypted for now.
+fn existing_recipients(opts: &SecretOpts, file: &Path) -> Result<Vec<String>> {
+    match opts.encryption {
+        EncryptionType::SOPS if opts.recipients.is_empty() => sops::recipients(file),
+        _ => Ok(opts.recipients.clone()),
+    }
+}
+
+/// Encrypt a plaintext file in place.
+pub fn encrypt(file: &Path, opts: &SecretOpts) -> Result<()> {
+    let secret = crate::util::get_secret();
+    let secret = secret.as_deref();
+
+    let content = read(file)?;
+    if is_encrypted(opts.encryption, file, &content) {
+        return Err(Error::create(
+            format!("{} is already encrypted", file.display()),
+            ErrorKind::Error,
+        ));
+    }
+
+    let recipients = match opts.encryption {
+        EncryptionType::SOPS => new_recipients(opts, secret)?,
+        EncryptionType::AnsibleVault => vec![],
+    };
+    write(
+        file,
+        &encrypt_secret(opts.encryption, file, &content, secret, &recipients)?,
+    )?;
+
+    println!("Encrypted {}", file.display());
+
+    Ok(())
+}
+
+/// Print the decrypted content of a file.
+pub fn view(file: &Path, opts: &SecretOpts) -> Result<()> {
+    let secret = crate::util::get_secret();
+    let content = read_secret_from_file(opts.encryption, file, secret.as_deref())?;
+
+    std::io::stdout()
+        .write_all(&content)
+        .context(ErrorKind::Error)?;
+
+    Ok(())
+}
+
+/// Run `$EDITOR` on the given file and wait for it to exit.
+fn run_editor(file: &Path) -> Result<()> {
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| DEFAULT_EDITOR.to_owned());
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or(DEFAULT_EDITOR);
+
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(file)
+        .status()
+        .map_err(|e| {
+            Error::create(
+                format!("Could not run editor '{}': {}", editor, e),
+                ErrorKind::Error,
+            )
+        })?;
+
+    if !status.success() {
+        return Err(Error::create(
+            format!("Editor '{}' exited with {}", editor, status),
+            ErrorKind::Error,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Decrypt a file to a temporary file, open it in an editor and encrypt the result in place.
+///
+/// SOPS files stay encrypted for the same recipients, unless others are given.
+pub fn edit(file: &Path, opts: &SecretOpts) -> Result<()> {
+    let secret = crate::util::get_secret();
+    let secret = secret.as_deref();
+
+    let content = read_secret_from_file(opts.encryption, file, secret)?;
+    let recipients = existing_recipients(opts, file)?;
+
+    // Keep the file name, so the editor recognizes the format.
+    let file_name = file
+        .file_name()
+        .map(|name| format!("-{}", name.to_string_lossy()))
+        .unwrap_or_default();
+    let temporary = tempfile::Builder::new()
+        .prefix("kuberwave-")
+        .suffix(&file_name)
+        .tempfile()
+        .context(ErrorKind::Error)?;
+    write(temporary.path(), &content)?;
+
+    run_editor(temporary.path())?;
+
+    let edited = read(temporary.path())?;
+    if edited == content {
+        println!("{} is unchanged", file.display());
+        return Ok(());
+    }
+
+    write(
+        file,
+        &encrypt_secret(opts.encryption, file, &edited, secret, &recipients)?,
+    )?;
+
+    println!("Encrypted {}", file.display());
+
+    Ok(())
+}
+
+/// The encrypted files of a manifest, with paths relative to the working directory.
+pub fn manifest_files(opts: &ProjectOpts) -> Result<Vec<(EncryptionType, PathBuf)>> {
+    let (manifest, _) = load_manifest(opts)?;
+    let base = opts.manifest.parent().unwrap();
+
+    Ok(manifest
+        .secret_files()
+        .into_iter()
+        .map(|(encryption, file)| (encryption, base.join(file)))
+        .collect())
+}
+
+/// Decrypt every file and encrypt it again: ansible-vault files with the password in
+/// `NEW_SECRET`, and SOPS files with a new data key for the given or their current recipients.
+///
+/// Nothing is written unless every file could be re-encrypted.
+pub fn rekey(files: &[(EncryptionType, PathBuf)], opts: &SecretOpts) -> Result<()> {
+    let secret = crate::util::get_secret();
+    let new_secret = std::env::var(NEW_SECRET_NAME).ok();
+
+    rekey_with(files, opts, secret.as_deref(), new_secret.as_deref())
+}
+
+/// Rekey files with the ansible-vault passwords `secret` and `new_secret`.
+fn rekey_with(
+    files: &[(EncryptionType, PathBuf)],
+    opts: &SecretOpts,
+    secret: Option<&str>,
+    new_secret: Option<&str>,
+) -> Result<()> {
+    let mut rekeyed = vec![];
+    for (encryption, file) in files {
+        let opts = SecretOpts {
+            encryption: *encryption,
+            recipients: opts.recipients.clone(),
+        };
+
+        let new_secret = match encryption {
+            EncryptionType::AnsibleVault => {
+                Some(new_secret.ok_or_else(|| ErrorKind::EnvError {
+                    name: NEW_SECRET_NAME.to_owned(),
+                })?)
+            }
+            EncryptionType::SOPS => None,
+        };
+
+        let content = read_secret_from_file(*encryption, file, secret)?;
+        let recipients = existing_recipients(&opts, file)?;
+
+        rekeyed.push((
+            file,
+            encrypt_secret(*encryption, file, &content, new_secret, &recipients)?,
+        ));
+    }
+
+    for (file, content) in &rekeyed {
+        write(file, content)?;
+
+        println!("Rekeyed {}", file.display());
+    }
+
+    println!("{} secrets rekeyed.", rekeyed.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rekey_writes_nothing_when_a_file_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = |name: &str, password: &str| {
+            let file = dir.path().join(name);
+            let content = encrypt_secret(
+                EncryptionType::AnsibleVault,
+                &file,
+                b"password: hunter2\n",
+                Some(password),
+                &[],
+            )
+            .unwrap();
+            write(&file, &content).unwrap();
+            (EncryptionType::AnsibleVault, file)
+        };
+        let opts = SecretOpts {
+            encryption: EncryptionType::AnsibleVault,
+            recipients: vec![],
+        };
+
+        let files = vec![vault("a.yml", "old"), vault("b.yml", "other")];
+        let before: Vec<Vec<u8>> = files.iter().map(|(_, f)| read(f).unwrap()).collect();
+
+        let error = rekey_with(&files, &opts, Some("old"), Some("new")).unwrap_err();
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::AnsibleVaultError {
+                file: files[1].1.clone()
+            }
+        );
+        let after: Vec<Vec<u8>> = files.iter().map(|(_, f)| read(f).unwrap()).collect();
+        assert_eq!(before, after);
+
+        rekey_with(&files[..1], &opts, Some("old"), Some("new")).unwrap();
+        let content = read_secret_from_file(EncryptionType::AnsibleVault, &files[0].1, Some("new"));
+        assert_eq!(content.unwrap(), b"password: hunter2\n");
+    }
+}
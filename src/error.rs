@@ -1,3 +1,6 @@
+// The `Fail` derive of failure expands to impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use failure::{Backtrace, Context, Fail};
 use std::fmt::{Debug, Display, Formatter};
 
@@ -10,6 +13,22 @@ impl Error {
     pub fn create<D: Display + Debug + Sync + Send + 'static>(message: D, kind: ErrorKind) -> Self {
         ::failure::err_msg(message).context(kind).into()
     }
+
+    pub fn kind(&self) -> &ErrorKind {
+        self.inner.get_context()
+    }
+
+    /// The error followed by its causes, to carry it in the kind of another error.
+    pub fn message(&self) -> std::string::String {
+        std::iter::once(self.to_string())
+            .chain(
+                (self as &dyn Fail)
+                    .iter_causes()
+                    .map(|cause| cause.to_string()),
+            )
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
 }
 
 impl Fail for Error {
@@ -56,6 +75,13 @@ pub enum ErrorKind {
     #[fail(display = "Failed to process the manifest file.")]
     ManifestError,
 
+    /// The `env` of the manifest could not be read before rendering it.
+    #[fail(
+        display = "The env of the manifest could not be read before rendering it: {}",
+        message
+    )]
+    EnvReadError { message: std::string::String },
+
     /// Failed to process the inventory file.
     #[fail(display = "Failed to process the inventory file.")]
     InventoryError,
@@ -76,6 +102,10 @@ pub enum ErrorKind {
     #[fail(display = "Failed to read file {:?}.", name)]
     FileReadError { name: std::path::PathBuf },
 
+    /// Failed to write file.
+    #[fail(display = "Failed to write file {:?}.", name)]
+    FileWriteError { name: std::path::PathBuf },
+
     /// Failed to open Ansible Vault.
     #[fail(
         display = "Failed to open Ansible vault: {:?}. Maybe the SECRET is missing?",
@@ -87,9 +117,58 @@ pub enum ErrorKind {
     #[fail(display = "Failed to read SOPS file: {:?}", file)]
     SOPSError { file: std::path::PathBuf },
 
-    /// Kubectl failed to run.
-    #[fail(display = "Kubectl failed to run.")]
-    KubectlError,
+    /// An age key could not be parsed.
+    #[fail(display = "Invalid age key in {}.", source)]
+    AgeKeyError { source: std::string::String },
+
+    /// Failed to load the cluster configuration.
+    #[fail(display = "Failed to load the Kubernetes configuration.")]
+    KubeconfigError,
+
+    /// Could not reach the Kubernetes API server.
+    #[fail(display = "Could not reach the Kubernetes API server at {}.", server)]
+    ConnectionError { server: std::string::String },
+
+    /// The Kubernetes API server rejected a request.
+    #[fail(
+        display = "Kubernetes API request failed with status {} ({}).",
+        code, reason
+    )]
+    ApiError {
+        code: u16,
+        reason: std::string::String,
+    },
+
+    /// The cluster does not serve the requested resource type.
+    #[fail(display = "The cluster does not serve {} in {}.", kind, api_version)]
+    UnknownResourceError {
+        api_version: std::string::String,
+        kind: std::string::String,
+    },
+
+    /// A resource file does not describe a valid Kubernetes object.
+    #[fail(display = "Invalid Kubernetes object in {}.", name)]
+    ObjectError { name: std::string::String },
+
+    /// The cluster rejected part of the configuration in a dry-run, with the reason per file.
+    #[fail(display = "Files were rejected by the cluster (dry-run).")]
+    DryRunError {
+        rejected: Vec<(std::string::String, std::string::String)>,
+    },
+
+    /// A hardmount cannot be stored as a ConfigMap or Secret.
+    #[fail(display = "Hardmount '{}' has {} problems.", name, problems)]
+    HardmountError {
+        name: std::string::String,
+        problems: usize,
+    },
+
+    /// A workload did not become ready, with the events and logs explaining why.
+    #[fail(display = "Rollout of {} did not become ready.", name)]
+    RolloutError {
+        name: std::string::String,
+        diagnostics: Vec<std::string::String>,
+    },
 
     /// Something unexpected happened.
     #[fail(display = "An unexpected error occured.")]
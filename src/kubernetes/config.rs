@@ -0,0 +1,302 @@
+use crate::error::{Error, ErrorKind, Result};
+use failure::ResultExt;
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct Kubeconfig {
+    #[serde(default)]
+    clusters: Vec<NamedCluster>,
+    #[serde(default)]
+    users: Vec<NamedUser>,
+    #[serde(default)]
+    contexts: Vec<NamedContext>,
+    current_context: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct NamedCluster {
+    name: String,
+    cluster: ClusterEntry,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct ClusterEntry {
+    server: String,
+    certificate_authority: Option<PathBuf>,
+    certificate_authority_data: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct NamedUser {
+    name: String,
+    #[serde(default)]
+    user: UserEntry,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+struct UserEntry {
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<PathBuf>,
+    client_certificate: Option<PathBuf>,
+    client_certificate_data: Option<String>,
+    client_key: Option<PathBuf>,
+    client_key_data: Option<String>,
+    exec: Option<ExecConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ExecConfig {
+    api_version: Option<String>,
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: Vec<ExecEnv>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ExecEnv {
+    name: String,
+    value: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct NamedContext {
+    name: String,
+    context: ContextEntry,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ContextEntry {
+    cluster: String,
+    user: Option<String>,
+    namespace: Option<String>,
+}
+
+/// A PEM encoded client certificate with its private key.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub certificate: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+/// Everything needed to talk to an API server, resolved from a kubeconfig or the pod environment.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub server: String,
+    pub namespace: Option<String>,
+    pub token: Option<String>,
+    pub certificate_authority: Option<Vec<u8>>,
+    pub identity: Option<Identity>,
+    pub insecure_skip_tls_verify: bool,
+}
+
+fn config_error(message: String) -> Error {
+    Error::create(message, ErrorKind::KubeconfigError)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    Ok(std::fs::read(path).context(ErrorKind::FileReadError {
+        name: path.to_owned(),
+    })?)
+}
+
+/// Inline `*-data` fields take precedence over their file counterparts, as they do in kubectl.
+fn read_data_or_file(
+    data: &Option<String>,
+    file: &Option<PathBuf>,
+    base: &Path,
+) -> Result<Option<Vec<u8>>> {
+    match (data, file) {
+        (Some(data), _) => Ok(Some(
+            base64::decode(data.trim()).context(ErrorKind::KubeconfigError)?,
+        )),
+        (None, Some(file)) => Ok(Some(read_file(&base.join(file))?)),
+        (None, None) => Ok(None),
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    if let Ok(paths) = std::env::var("KUBECONFIG") {
+        return std::env::split_paths(&paths).find(|path| path.is_file());
+    }
+
+    let path = PathBuf::from(std::env::var_os("HOME")?).join(".kube/config");
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+impl Config {
+    /// Load the configuration from an explicit kubeconfig, from `KUBECONFIG` or `~/.kube/config`,
+    /// or from the service account of the pod we are running in, in that order.
+    pub fn load(kubeconfig: Option<&Path>) -> Result<Config> {
+        match kubeconfig.map(Path::to_path_buf).or_else(default_path) {
+            Some(path) => Config::from_kubeconfig(&path),
+            None => Config::in_cluster(),
+        }
+    }
+
+    pub fn from_kubeconfig(path: &Path) -> Result<Config> {
+        let content = read_file(path).context(ErrorKind::KubeconfigError)?;
+        let kubeconfig: Kubeconfig = serde_yaml::from_slice(&content)
+            .context(ErrorKind::ParseError {
+                name: path.to_owned(),
+            })
+            .context(ErrorKind::KubeconfigError)?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let context_name = kubeconfig
+            .current_context
+            .as_ref()
+            .ok_or_else(|| config_error(format!("No current-context set in {:?}", path)))?;
+        let context = kubeconfig
+            .contexts
+            .iter()
+            .find(|c| &c.name == context_name)
+            .map(|c| &c.context)
+            .ok_or_else(|| config_error(format!("Context '{}' not found", context_name)))?;
+        let cluster = kubeconfig
+            .clusters
+            .iter()
+            .find(|c| c.name == context.cluster)
+            .map(|c| &c.cluster)
+            .ok_or_else(|| config_error(format!("Cluster '{}' not found", context.cluster)))?;
+        let user = match &context.user {
+            Some(name) => kubeconfig
+                .users
+                .iter()
+                .find(|u| &u.name == name)
+                .map(|u| u.user.clone())
+                .ok_or_else(|| config_error(format!("User '{}' not found", name)))?,
+            None => UserEntry::default(),
+        };
+
+        let token = match (&user.token, &user.token_file, &user.exec) {
+            (Some(token), _, _) => Some(token.clone()),
+            (None, Some(file), _) => Some(
+                String::from_utf8(read_file(&base.join(file))?)
+                    .context(ErrorKind::KubeconfigError)?,
+            ),
+            (None, None, Some(exec)) => Some(exec.token()?),
+            (None, None, None) => None,
+        };
+
+        let certificate = read_data_or_file(
+            &user.client_certificate_data,
+            &user.client_certificate,
+            base,
+        )?;
+        let key = read_data_or_file(&user.client_key_data, &user.client_key, base)?;
+        let identity = match (certificate, key) {
+            (Some(certificate), Some(key)) => Some(Identity { certificate, key }),
+            (None, None) => None,
+            _ => {
+                return Err(config_error(
+                    "A client certificate requires both a certificate and a key".to_owned(),
+                ))
+            }
+        };
+
+        Ok(Config {
+            server: cluster.server.clone(),
+            namespace: context.namespace.clone(),
+            token: token.map(|t| t.trim().to_owned()),
+            certificate_authority: read_data_or_file(
+                &cluster.certificate_authority_data,
+                &cluster.certificate_authority,
+                base,
+            )?,
+            identity,
+            insecure_skip_tls_verify: cluster.insecure_skip_tls_verify,
+        })
+    }
+
+    pub fn in_cluster() -> Result<Config> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+            config_error("No kubeconfig found and not running in a cluster".to_owned())
+        })?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_owned());
+        let base = Path::new(SERVICE_ACCOUNT_DIR);
+
+        // IPv6 service addresses need brackets in a URL.
+        let host = if host.contains(':') {
+            format!("[{}]", host)
+        } else {
+            host
+        };
+
+        Ok(Config {
+            server: format!("https://{}:{}", host, port),
+            namespace: std::fs::read_to_string(base.join("namespace"))
+                .ok()
+                .map(|n| n.trim().to_owned()),
+            token: Some(
+                String::from_utf8(read_file(&base.join("token"))?)
+                    .context(ErrorKind::KubeconfigError)?
+                    .trim()
+                    .to_owned(),
+            ),
+            certificate_authority: Some(read_file(&base.join("ca.crt"))?),
+            identity: None,
+            insecure_skip_tls_verify: false,
+        })
+    }
+}
+
+impl ExecConfig {
+    /// Run a client-go credential plugin, such as `gke-gcloud-auth-plugin`, and take its bearer token.
+    fn token(&self) -> Result<String> {
+        let api_version = self
+            .api_version
+            .as_deref()
+            .unwrap_or("client.authentication.k8s.io/v1beta1");
+        let exec_info = serde_json::json!({
+            "apiVersion": api_version,
+            "kind": "ExecCredential",
+            "spec": { "interactive": false },
+        });
+
+        let mut command = std::process::Command::new(&self.command);
+        command
+            .args(&self.args)
+            .env("KUBERNETES_EXEC_INFO", exec_info.to_string())
+            .stderr(std::process::Stdio::inherit());
+        for env in &self.env {
+            command.env(&env.name, &env.value);
+        }
+
+        let output = command.output().context(ErrorKind::KubeconfigError)?;
+        if !output.status.success() {
+            return Err(config_error(format!(
+                "Credential plugin '{}' exited with {}",
+                self.command, output.status
+            )));
+        }
+
+        let credential: serde_json::Value =
+            serde_json::from_slice(&output.stdout).context(ErrorKind::KubeconfigError)?;
+
+        credential["status"]["token"]
+            .as_str()
+            .map(str::to_owned)
+            .ok_or_else(|| {
+                config_error(format!(
+                    "Credential plugin '{}' did not return a token",
+                    self.command
+                ))
+            })
+    }
+}
@@ -0,0 +1,533 @@
+mod config;
+pub mod rollout;
+mod tls;
+
+pub use config::Config;
+
+use crate::error::{Error, ErrorKind, Result};
+use crate::resourcefile::Resourcefile;
+use failure::{Fail, ResultExt};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Field manager used for server-side apply, shown in `metadata.managedFields`.
+pub const FIELD_MANAGER: &str = "kuberwave";
+
+/// The annotation in which client-side `kubectl apply` stores the configuration it applied.
+const LAST_APPLIED_ANNOTATION: &str = "kubectl.kubernetes.io/last-applied-configuration";
+
+/// A resource type as served by the API server discovery endpoints.
+#[derive(Debug, Clone)]
+struct ApiResource {
+    name: String,
+    kind: String,
+    namespaced: bool,
+}
+
+/// The identifying fields of a Kubernetes object.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ObjectRef {
+    pub api_version: String,
+    pub kind: String,
+    pub name: String,
+    pub namespace: Option<String>,
+}
+
+impl ObjectRef {
+    pub fn from_object(object: &Value) -> Option<ObjectRef> {
+        Some(ObjectRef {
+            api_version: object["apiVersion"].as_str()?.to_owned(),
+            kind: object["kind"].as_str()?.to_owned(),
+            name: object["metadata"]["name"].as_str()?.to_owned(),
+            namespace: object["metadata"]["namespace"].as_str().map(str::to_owned),
+        })
+    }
+}
+
+impl std::fmt::Display for ObjectRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.kind.to_lowercase(), self.name)
+    }
+}
+
+/// A minimal client for the Kubernetes API server.
+pub struct Client {
+    agent: ureq::Agent,
+    server: String,
+    token: Option<String>,
+    namespace: String,
+    resources: RefCell<HashMap<String, Vec<ApiResource>>>,
+}
+
+impl Client {
+    /// Connect using the given kubeconfig (or the default one), optionally overriding its
+    /// credentials with a bearer token and its namespace.
+    pub fn new(
+        kubeconfig: Option<std::path::PathBuf>,
+        token: Option<String>,
+        namespace: Option<String>,
+    ) -> Result<Client> {
+        let config = Config::load(kubeconfig.as_deref())?;
+        Client::from_config(config, token, namespace)
+    }
+
+    pub fn from_config(
+        config: Config,
+        token: Option<String>,
+        namespace: Option<String>,
+    ) -> Result<Client> {
+        let agent = ureq::AgentBuilder::new()
+            .tls_config(tls::client_config(&config)?)
+            .build();
+
+        Ok(Client {
+            agent,
+            server: config.server.trim_end_matches('/').to_owned(),
+            token: token.or(config.token).map(|t| t.trim().to_owned()),
+            namespace: namespace
+                .or(config.namespace)
+                .unwrap_or_else(|| "default".to_owned())
+                .trim()
+                .to_owned(),
+            resources: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self
+            .agent
+            .request(method, &format!("{}{}", self.server, path))
+            .set("Accept", "application/json");
+
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    /// Pass a successful response through, or turn it into a typed error carrying the `Status`
+    /// message of the server.
+    fn check(
+        &self,
+        result: std::result::Result<ureq::Response, ureq::Error>,
+    ) -> Result<ureq::Response> {
+        match result {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(code, response)) => {
+                let status: Value = response.into_json().unwrap_or(Value::Null);
+                let reason = status["reason"].as_str().unwrap_or("Unknown").to_owned();
+                let message = status["message"]
+                    .as_str()
+                    .unwrap_or("no message")
+                    .to_owned();
+
+                Err(Error::create(message, ErrorKind::ApiError { code, reason }))
+            }
+            Err(e) => Err(Error::create(
+                e.to_string(),
+                ErrorKind::ConnectionError {
+                    server: self.server.clone(),
+                },
+            )),
+        }
+    }
+
+    fn handle(&self, result: std::result::Result<ureq::Response, ureq::Error>) -> Result<Value> {
+        Ok(self
+            .check(result)?
+            .into_json()
+            .context(ErrorKind::ConnectionError {
+                server: self.server.clone(),
+            })?)
+    }
+
+    fn discover(&self, api_version: &str) -> Result<Vec<ApiResource>> {
+        if let Some(resources) = self.resources.borrow().get(api_version) {
+            return Ok(resources.clone());
+        }
+
+        let list = match self.handle(self.request("GET", &api_prefix(api_version)).call()) {
+            Ok(list) => list,
+            Err(e) if is_not_found(&e) => Value::Null,
+            Err(e) => return Err(e),
+        };
+
+        let resources: Vec<ApiResource> = list["resources"]
+            .as_array()
+            .map(|rs| {
+                rs.iter()
+                    .filter_map(|r| {
+                        Some(ApiResource {
+                            name: r["name"].as_str()?.to_owned(),
+                            kind: r["kind"].as_str()?.to_owned(),
+                            namespaced: r["namespaced"].as_bool()?,
+                        })
+                    })
+                    // Skip subresources such as `deployments/scale`.
+                    .filter(|r| !r.name.contains('/'))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        self.resources
+            .borrow_mut()
+            .insert(api_version.to_owned(), resources.clone());
+
+        Ok(resources)
+    }
+
+    /// The API path of a resource type; namespaced resources default to the client namespace.
+    fn collection_path(
+        &self,
+        api_version: &str,
+        kind: &str,
+        namespace: Option<&str>,
+    ) -> Result<String> {
+        let resource = self
+            .discover(api_version)?
+            .into_iter()
+            .find(|r| r.kind == kind)
+            .ok_or_else(|| ErrorKind::UnknownResourceError {
+                api_version: api_version.to_owned(),
+                kind: kind.to_owned(),
+            })?;
+
+        let prefix = api_prefix(api_version);
+
+        Ok(if resource.namespaced {
+            format!(
+                "{}/namespaces/{}/{}",
+                prefix,
+                namespace.unwrap_or(&self.namespace),
+                resource.name
+            )
+        } else {
+            format!("{}/{}", prefix, resource.name)
+        })
+    }
+
+    fn object_path(&self, object: &ObjectRef) -> Result<String> {
+        let collection = self.collection_path(
+            &object.api_version,
+            &object.kind,
+            object.namespace.as_deref(),
+        )?;
+
+        Ok(format!("{}/{}", collection, object.name))
+    }
+
+    /// Fetch an object, returning `None` if it does not exist.
+    pub fn get(&self, object: &ObjectRef) -> Result<Option<Value>> {
+        let path = self.object_path(object)?;
+
+        match self.handle(self.request("GET", &path).call()) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list_query(
+        &self,
+        api_version: &str,
+        kind: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Vec<Value>> {
+        let path = self.collection_path(api_version, kind, None)?;
+
+        let mut request = self.request("GET", &path);
+        for (key, value) in query {
+            request = request.query(key, value);
+        }
+        let list = self.handle(request.call())?;
+
+        // Items in a list response do not repeat their type.
+        Ok(list["items"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|mut item| {
+                item["apiVersion"] = Value::from(api_version);
+                item["kind"] = Value::from(kind);
+                item
+            })
+            .collect())
+    }
+
+    /// List the objects of a resource type in the client namespace matching a label selector.
+    pub fn list(&self, api_version: &str, kind: &str, label_selector: &str) -> Result<Vec<Value>> {
+        self.list_query(api_version, kind, &[("labelSelector", label_selector)])
+    }
+
+    /// List the events about an object in the client namespace.
+    pub fn events(&self, object: &ObjectRef) -> Result<Vec<Value>> {
+        let selector = format!(
+            "involvedObject.kind={},involvedObject.name={}",
+            object.kind, object.name
+        );
+
+        self.list_query("v1", "Event", &[("fieldSelector", &selector)])
+    }
+
+    /// The last lines of the log of a container, or of its previous instance if it restarted.
+    pub fn logs(&self, pod: &str, container: &str, previous: bool, lines: u32) -> Result<String> {
+        let path = format!("/api/v1/namespaces/{}/pods/{}/log", self.namespace, pod);
+
+        let response = self.check(
+            self.request("GET", &path)
+                .set("Accept", "text/plain")
+                .query("container", container)
+                .query("previous", &previous.to_string())
+                .query("tailLines", &lines.to_string())
+                .call(),
+        )?;
+
+        Ok(response.into_string().context(ErrorKind::ConnectionError {
+            server: self.server.clone(),
+        })?)
+    }
+
+    /// Delete an object and, in the background, everything it owns.
+    pub fn delete(&self, object: &ObjectRef) -> Result<()> {
+        let path = self.object_path(object)?;
+
+        match self.handle(
+            self.request("DELETE", &path)
+                .query("propagationPolicy", "Background")
+                .call(),
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) if is_not_found(&e) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn server_side_apply(&self, object: &Value, dry_run: bool) -> Result<Value> {
+        let object_ref = ObjectRef::from_object(object).ok_or_else(|| ErrorKind::ObjectError {
+            name: object.to_string(),
+        })?;
+        let path = self.object_path(&object_ref)?;
+
+        let mut request = self
+            .request("PATCH", &path)
+            .set("Content-Type", "application/apply-patch+yaml")
+            .query("fieldManager", FIELD_MANAGER)
+            .query("force", "true");
+        if dry_run {
+            request = request.query("dryRun", "All");
+        }
+
+        self.handle(request.send_string(&object.to_string()))
+            .map_err(|e| {
+                // Keep the typed error, but mention which object was rejected.
+                let message = e.cause().map(|c| c.to_string()).unwrap_or_default();
+                Error::create(format!("{}: {}", object_ref, message), e.kind().clone())
+            })
+    }
+
+    /// Replace the managed fields of an object, failing if it changed since it was read.
+    fn patch_managed_fields(&self, object: &Value, managed_fields: Vec<Value>) -> Result<Value> {
+        let object_ref = ObjectRef::from_object(object).ok_or_else(|| ErrorKind::ObjectError {
+            name: object.to_string(),
+        })?;
+        let path = self.object_path(&object_ref)?;
+
+        let patch = serde_json::json!([
+            {
+                "op": "test",
+                "path": "/metadata/resourceVersion",
+                "value": object["metadata"]["resourceVersion"],
+            },
+            {
+                "op": "replace",
+                "path": "/metadata/managedFields",
+                "value": managed_fields,
+            },
+        ]);
+
+        self.handle(
+            self.request("PATCH", &path)
+                .set("Content-Type", "application/json-patch+json")
+                .send_string(&patch.to_string()),
+        )
+    }
+
+    /// Server-side apply a single object.
+    ///
+    /// Objects last applied with client-side `kubectl apply` are taken over first, like
+    /// `kubectl apply --server-side` does, so fields removed from the configuration are removed
+    /// from the object instead of staying owned by `kubectl`.
+    pub fn apply_object(&self, object: &Value) -> Result<Value> {
+        let applied = self.server_side_apply(object, false)?;
+
+        match upgrade_managed_fields(&applied) {
+            Some(managed_fields) => {
+                self.patch_managed_fields(&applied, managed_fields)?;
+                self.server_side_apply(object, false)
+            }
+            None => Ok(applied),
+        }
+    }
+
+    /// Server-side apply a single object without persisting it, returning the object as it would
+    /// be stored.
+    pub fn apply_object_dry_run(&self, object: &Value) -> Result<Value> {
+        self.server_side_apply(object, true)
+    }
+
+    /// Apply every object in a resource file.
+    pub fn apply(&self, file: &Resourcefile) -> Result<()> {
+        for object in file.objects()? {
+            self.apply_object(&object)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Add the fields of a `fieldsV1` set to another one.
+fn merge_fields(into: &mut Value, from: &Value) {
+    if let (Some(into), Some(from)) = (into.as_object_mut(), from.as_object()) {
+        for (key, value) in from {
+            merge_fields(
+                into.entry(key.clone())
+                    .or_insert_with(|| Value::Object(Default::default())),
+                value,
+            );
+        }
+    }
+}
+
+/// The managed fields of an object with the fields of client-side `kubectl apply` moved to the
+/// apply entry of kuberwave, or `None` if the object was not applied client-side.
+///
+/// Like `kubectl`, the managers of client-side apply are those whose updates own the
+/// last-applied-configuration annotation.
+fn upgrade_managed_fields(object: &Value) -> Option<Vec<Value>> {
+    let entries = object["metadata"]["managedFields"].as_array()?;
+    let annotation = format!("f:{}", LAST_APPLIED_ANNOTATION);
+    let is_update = |entry: &Value| {
+        entry["operation"] == "Update" && entry["subresource"].as_str().unwrap_or("").is_empty()
+    };
+
+    let managers: Vec<&Value> = entries
+        .iter()
+        .filter(|entry| {
+            is_update(entry)
+                && !entry["fieldsV1"]["f:metadata"]["f:annotations"][&annotation].is_null()
+        })
+        .map(|entry| &entry["manager"])
+        .collect();
+    if managers.is_empty() {
+        return None;
+    }
+
+    let mut fields = Value::Object(Default::default());
+    let mut apply_entry = None;
+    let mut upgraded = vec![];
+    for entry in entries {
+        if is_update(entry) && managers.contains(&&entry["manager"]) {
+            merge_fields(&mut fields, &entry["fieldsV1"]);
+        } else if entry["manager"] == FIELD_MANAGER
+            && entry["operation"] == "Apply"
+            && entry["subresource"].as_str().unwrap_or("").is_empty()
+        {
+            apply_entry = Some(entry.clone());
+        } else {
+            upgraded.push(entry.clone());
+        }
+    }
+
+    let mut apply_entry = apply_entry.unwrap_or_else(|| {
+        serde_json::json!({
+            "manager": FIELD_MANAGER,
+            "operation": "Apply",
+            "apiVersion": object["apiVersion"],
+            "fieldsType": "FieldsV1",
+            "fieldsV1": {},
+        })
+    });
+    merge_fields(&mut apply_entry["fieldsV1"], &fields);
+    upgraded.push(apply_entry);
+
+    Some(upgraded)
+}
+
+/// The core group is served under `/api`, every other group under `/apis`.
+fn api_prefix(api_version: &str) -> String {
+    if api_version.contains('/') {
+        format!("/apis/{}", api_version)
+    } else {
+        format!("/api/{}", api_version)
+    }
+}
+
+pub fn is_not_found(error: &Error) -> bool {
+    matches!(error.kind(), ErrorKind::ApiError { code: 404, .. })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn client_side_apply_fields_move_to_kuberwave() {
+        let object = json!({
+            "apiVersion": "networking.k8s.io/v1",
+            "metadata": {
+                "managedFields": [
+                    {
+                        "manager": "kubectl-client-side-apply",
+                        "operation": "Update",
+                        "fieldsV1": {"f:metadata": {"f:annotations": {
+                            ".": {},
+                            "f:kubectl.kubernetes.io/last-applied-configuration": {},
+                            "f:kubernetes.io/ingress.class": {},
+                        }}},
+                    },
+                    {
+                        "manager": "kuberwave",
+                        "operation": "Apply",
+                        "fieldsV1": {"f:spec": {"f:rules": {}}},
+                    },
+                    {
+                        "manager": "nginx-ingress-controller",
+                        "operation": "Update",
+                        "subresource": "status",
+                        "fieldsV1": {"f:status": {}},
+                    },
+                ],
+            },
+        });
+
+        let upgraded = upgrade_managed_fields(&object).unwrap();
+        assert_eq!(upgraded.len(), 2);
+        assert_eq!(upgraded[0]["manager"], "nginx-ingress-controller");
+        assert_eq!(
+            upgraded[1],
+            json!({
+                "manager": "kuberwave",
+                "operation": "Apply",
+                "fieldsV1": {
+                    "f:spec": {"f:rules": {}},
+                    "f:metadata": {"f:annotations": {
+                        ".": {},
+                        "f:kubectl.kubernetes.io/last-applied-configuration": {},
+                        "f:kubernetes.io/ingress.class": {},
+                    }},
+                },
+            })
+        );
+
+        // Once taken over, there is nothing left to upgrade.
+        let object = json!({"metadata": {"managedFields": upgraded}});
+        assert!(upgrade_managed_fields(&object).is_none());
+    }
+}
@@ -0,0 +1,422 @@
+use crate::error::{Error, ErrorKind, Result};
+use crate::kubernetes::{Client, ObjectRef};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The workload kinds we know how to wait for.
+pub const WORKLOAD_KINDS: &[&str] = &["Deployment", "StatefulSet", "DaemonSet", "Job"];
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Container states from which a pod does not recover without a new deploy.
+const FATAL_REASONS: &[&str] = &[
+    "CrashLoopBackOff",
+    "ImagePullBackOff",
+    "InvalidImageName",
+    "CreateContainerConfigError",
+];
+
+const LOG_LINES: u32 = 50;
+
+#[derive(Debug, PartialEq)]
+enum Status {
+    Ready,
+    Progressing(String),
+    Failed(String),
+}
+
+fn int(value: &Value) -> i64 {
+    value.as_i64().unwrap_or(0)
+}
+
+fn condition<'a>(object: &'a Value, kind: &str) -> Option<&'a Value> {
+    object["status"]["conditions"]
+        .as_array()?
+        .iter()
+        .find(|c| c["type"] == kind && c["status"] == "True")
+}
+
+/// Whether the controller has seen the latest spec; otherwise its status is about an older one.
+fn observed(object: &Value) -> bool {
+    int(&object["status"]["observedGeneration"]) >= int(&object["metadata"]["generation"])
+}
+
+fn deployment_status(object: &Value) -> Status {
+    if let Some(c) = object["status"]["conditions"].as_array().and_then(|cs| {
+        cs.iter()
+            .find(|c| c["reason"] == "ProgressDeadlineExceeded")
+    }) {
+        return Status::Failed(c["message"].as_str().unwrap_or_default().to_owned());
+    }
+
+    let status = &object["status"];
+    let desired = object["spec"]["replicas"].as_i64().unwrap_or(1);
+    let updated = int(&status["updatedReplicas"]);
+
+    if !observed(object) {
+        Status::Progressing("waiting for the rollout to start".to_owned())
+    } else if updated < desired {
+        Status::Progressing(format!("{} of {} replicas updated", updated, desired))
+    } else if int(&status["replicas"]) > updated {
+        Status::Progressing(format!(
+            "{} old replicas pending termination",
+            int(&status["replicas"]) - updated
+        ))
+    } else if int(&status["availableReplicas"]) < updated {
+        Status::Progressing(format!(
+            "{} of {} updated replicas available",
+            int(&status["availableReplicas"]),
+            updated
+        ))
+    } else {
+        Status::Ready
+    }
+}
+
+fn statefulset_status(object: &Value) -> Status {
+    let status = &object["status"];
+    let desired = object["spec"]["replicas"].as_i64().unwrap_or(1);
+
+    if !observed(object) {
+        Status::Progressing("waiting for the rollout to start".to_owned())
+    } else if int(&status["readyReplicas"]) < desired {
+        Status::Progressing(format!(
+            "{} of {} replicas ready",
+            int(&status["readyReplicas"]),
+            desired
+        ))
+    } else if object["spec"]["updateStrategy"]["type"] != "OnDelete"
+        && status["updateRevision"] != status["currentRevision"]
+    {
+        Status::Progressing(format!(
+            "{} of {} replicas updated",
+            int(&status["updatedReplicas"]),
+            desired
+        ))
+    } else {
+        Status::Ready
+    }
+}
+
+fn daemonset_status(object: &Value) -> Status {
+    let status = &object["status"];
+    let desired = int(&status["desiredNumberScheduled"]);
+
+    if !observed(object) {
+        Status::Progressing("waiting for the rollout to start".to_owned())
+    } else if int(&status["updatedNumberScheduled"]) < desired {
+        Status::Progressing(format!(
+            "{} of {} pods updated",
+            int(&status["updatedNumberScheduled"]),
+            desired
+        ))
+    } else if int(&status["numberAvailable"]) < desired {
+        Status::Progressing(format!(
+            "{} of {} pods available",
+            int(&status["numberAvailable"]),
+            desired
+        ))
+    } else {
+        Status::Ready
+    }
+}
+
+fn job_status(object: &Value) -> Status {
+    if condition(object, "Complete").is_some() {
+        Status::Ready
+    } else if let Some(c) = condition(object, "Failed") {
+        Status::Failed(c["message"].as_str().unwrap_or_default().to_owned())
+    } else {
+        Status::Progressing(format!(
+            "{} pods active, {} succeeded",
+            int(&object["status"]["active"]),
+            int(&object["status"]["succeeded"])
+        ))
+    }
+}
+
+fn status(object: &Value) -> Status {
+    match object["kind"].as_str() {
+        Some("Deployment") => deployment_status(object),
+        Some("StatefulSet") => statefulset_status(object),
+        Some("DaemonSet") => daemonset_status(object),
+        Some("Job") => job_status(object),
+        _ => Status::Ready,
+    }
+}
+
+/// The label selector matching the pods of a workload.
+fn pod_selector(object: &Value) -> Option<String> {
+    let labels = object["spec"]["selector"]["matchLabels"].as_object()?;
+
+    Some(
+        labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v.as_str().unwrap_or_default()))
+            .collect::<Vec<String>>()
+            .join(","),
+    )
+}
+
+fn pods(client: &Client, object: &Value) -> Result<Vec<Value>> {
+    match pod_selector(object) {
+        Some(selector) => client.list("v1", "Pod", &selector),
+        None => Ok(vec![]),
+    }
+}
+
+fn container_statuses(pod: &Value) -> impl Iterator<Item = &Value> {
+    pod["status"]["initContainerStatuses"]
+        .as_array()
+        .into_iter()
+        .chain(pod["status"]["containerStatuses"].as_array())
+        .flatten()
+}
+
+/// A container that is stuck in a state it will not recover from, as `(container, reason)`.
+fn fatal_container(pod: &Value) -> Option<(String, String)> {
+    container_statuses(pod).find_map(|c| {
+        let reason = c["state"]["waiting"]["reason"].as_str()?;
+        if FATAL_REASONS.contains(&reason) {
+            Some((c["name"].as_str()?.to_owned(), reason.to_owned()))
+        } else {
+            None
+        }
+    })
+}
+
+/// The events of a workload and its pods, and the logs of containers that are not ready.
+fn diagnostics(client: &Client, object_ref: &ObjectRef, object: &Value) -> Result<Vec<String>> {
+    let mut lines = vec!["Events:".to_owned()];
+    let mut events = |object_ref: &ObjectRef| -> Result<()> {
+        for event in client.events(object_ref)? {
+            lines.push(format!(
+                "  {} {} {}: {}",
+                event["type"].as_str().unwrap_or_default(),
+                object_ref,
+                event["reason"].as_str().unwrap_or_default(),
+                event["message"].as_str().unwrap_or_default().trim(),
+            ));
+        }
+        Ok(())
+    };
+
+    events(object_ref)?;
+
+    let pods = pods(client, object)?;
+    for pod in &pods {
+        if let Some(pod_ref) = ObjectRef::from_object(pod) {
+            events(&pod_ref)?;
+        }
+    }
+
+    for pod in &pods {
+        let pod_name = pod["metadata"]["name"].as_str().unwrap_or_default();
+        for container in container_statuses(pod) {
+            if container["ready"] == true || container["state"]["terminated"]["exitCode"] == 0 {
+                continue;
+            }
+
+            let name = container["name"].as_str().unwrap_or_default();
+            let previous = int(&container["restartCount"]) > 0
+                && container["state"]["running"].is_null()
+                && container["state"]["terminated"].is_null();
+
+            lines.push(format!(
+                "Logs of {}/{}{}:",
+                pod_name,
+                name,
+                if previous { " (previous)" } else { "" }
+            ));
+            match client.logs(pod_name, name, previous, LOG_LINES) {
+                Ok(logs) => lines.extend(logs.lines().map(|line| format!("  {}", line))),
+                Err(e) => lines.push(format!("  unavailable: {}", e)),
+            }
+        }
+    }
+
+    Ok(lines)
+}
+
+/// Wait until every workload is ready, failing early when one of them can no longer succeed.
+pub fn wait(client: &Client, workloads: &[ObjectRef], timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut pending: Vec<&ObjectRef> = workloads.iter().collect();
+    let mut reported: HashMap<&ObjectRef, String> = HashMap::new();
+
+    while !pending.is_empty() {
+        let mut still_pending = vec![];
+
+        for object_ref in pending {
+            let object = client.get(object_ref)?.ok_or_else(|| {
+                Error::create(
+                    format!("{} disappeared", object_ref),
+                    ErrorKind::RolloutError {
+                        name: object_ref.to_string(),
+                        diagnostics: vec![],
+                    },
+                )
+            })?;
+
+            let failure = match status(&object) {
+                Status::Ready => {
+                    println!("{} is ready.", object_ref);
+                    continue;
+                }
+                Status::Failed(message) => Some(message),
+                Status::Progressing(message) => {
+                    let fatal = pods(client, &object)?.iter().find_map(fatal_container);
+
+                    match fatal {
+                        Some((container, reason)) => {
+                            Some(format!("container {} is in {}", container, reason))
+                        }
+                        None if Instant::now() >= deadline => {
+                            Some(format!("timed out after {:?}: {}", timeout, message))
+                        }
+                        None => {
+                            // Only report progress when it changes.
+                            if reported.get(object_ref) != Some(&message) {
+                                println!("Waiting for {}: {}", object_ref, message);
+                                reported.insert(object_ref, message);
+                            }
+                            still_pending.push(object_ref);
+                            None
+                        }
+                    }
+                }
+            };
+
+            if let Some(message) = failure {
+                // The diagnostics are best-effort, the rollout failure is what matters.
+                let diagnostics = diagnostics(client, object_ref, &object)
+                    .unwrap_or_else(|e| vec![format!("Diagnostics unavailable: {}", e.message())]);
+
+                return Err(Error::create(
+                    message,
+                    ErrorKind::RolloutError {
+                        name: object_ref.to_string(),
+                        diagnostics,
+                    },
+                ));
+            }
+        }
+
+        pending = still_pending;
+        if !pending.is_empty() {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn deployment(status: Value) -> Value {
+        json!({
+            "kind": "Deployment",
+            "metadata": {"generation": 2},
+            "spec": {"replicas": 2},
+            "status": status,
+        })
+    }
+
+    #[test]
+    fn deployment_status_follows_the_rollout() {
+        let old = deployment(json!({"observedGeneration": 1, "replicas": 2}));
+        assert_eq!(
+            status(&old),
+            Status::Progressing("waiting for the rollout to start".to_owned())
+        );
+
+        let rolling = deployment(json!({
+            "observedGeneration": 2,
+            "replicas": 3,
+            "updatedReplicas": 2,
+            "availableReplicas": 2,
+        }));
+        assert_eq!(
+            status(&rolling),
+            Status::Progressing("1 old replicas pending termination".to_owned())
+        );
+
+        let ready = deployment(json!({
+            "observedGeneration": 2,
+            "replicas": 2,
+            "updatedReplicas": 2,
+            "availableReplicas": 2,
+            "conditions": [{
+                "type": "Progressing",
+                "status": "True",
+                "reason": "NewReplicaSetAvailable",
+            }],
+        }));
+        assert_eq!(status(&ready), Status::Ready);
+
+        let stuck = deployment(json!({
+            "observedGeneration": 2,
+            "conditions": [{
+                "type": "Progressing",
+                "status": "False",
+                "reason": "ProgressDeadlineExceeded",
+                "message": "ReplicaSet \"web-5d8\" has timed out progressing.",
+            }],
+        }));
+        assert_eq!(
+            status(&stuck),
+            Status::Failed("ReplicaSet \"web-5d8\" has timed out progressing.".to_owned())
+        );
+    }
+
+    #[test]
+    fn job_status_reports_failures() {
+        let running = json!({"kind": "Job", "status": {"active": 1}});
+        assert_eq!(
+            status(&running),
+            Status::Progressing("1 pods active, 0 succeeded".to_owned())
+        );
+
+        let failed = json!({
+            "kind": "Job",
+            "status": {"conditions": [{
+                "type": "Failed",
+                "status": "True",
+                "reason": "BackoffLimitExceeded",
+                "message": "Job has reached the specified backoff limit",
+            }]},
+        });
+        assert_eq!(
+            status(&failed),
+            Status::Failed("Job has reached the specified backoff limit".to_owned())
+        );
+
+        let complete = json!({
+            "kind": "Job",
+            "status": {"conditions": [{"type": "Complete", "status": "True"}], "succeeded": 1},
+        });
+        assert_eq!(status(&complete), Status::Ready);
+    }
+
+    #[test]
+    fn image_pull_errors_are_retried_before_they_are_fatal() {
+        let pod = |reason: &str| {
+            json!({"status": {"containerStatuses": [{
+                "name": "web",
+                "ready": false,
+                "state": {"waiting": {"reason": reason}},
+            }]}})
+        };
+
+        assert_eq!(fatal_container(&pod("ErrImagePull")), None);
+        assert_eq!(fatal_container(&pod("ContainerCreating")), None);
+        assert_eq!(
+            fatal_container(&pod("ImagePullBackOff")),
+            Some(("web".to_owned(), "ImagePullBackOff".to_owned()))
+        );
+    }
+}
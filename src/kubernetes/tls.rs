@@ -0,0 +1,112 @@
+use crate::error::{Error, ErrorKind, Result};
+use crate::kubernetes::Config;
+use failure::ResultExt;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::sync::Arc;
+
+fn tls_error<D: std::fmt::Display>(e: D) -> Error {
+    Error::create(e.to_string(), ErrorKind::KubeconfigError)
+}
+
+fn parse_certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = rustls_pemfile::certs(&mut &pem[..])
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .context(ErrorKind::KubeconfigError)?;
+
+    if certificates.is_empty() {
+        return Err(tls_error("No PEM certificates found"));
+    }
+
+    Ok(certificates)
+}
+
+/// Accepts any server certificate, for clusters configured with `insecure-skip-tls-verify`.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Trust the cluster CA from the configuration if there is one, and the public web PKI otherwise.
+pub fn client_config(config: &Config) -> Result<Arc<rustls::ClientConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?;
+
+    let builder = if config.insecure_skip_tls_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+    } else {
+        let mut roots = rustls::RootCertStore::empty();
+        match &config.certificate_authority {
+            Some(pem) => {
+                for certificate in parse_certificates(pem)? {
+                    roots.add(certificate).map_err(tls_error)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        builder.with_root_certificates(roots)
+    };
+
+    let tls = match &config.identity {
+        Some(identity) => {
+            let key = rustls_pemfile::private_key(&mut &identity.key[..])
+                .context(ErrorKind::KubeconfigError)?
+                .ok_or_else(|| tls_error("No PEM private key found"))?;
+            builder
+                .with_client_auth_cert(parse_certificates(&identity.certificate)?, key)
+                .map_err(tls_error)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(tls))
+}
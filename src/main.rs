@@ -4,14 +4,21 @@ pub mod error;
 pub mod project;
 pub mod resourcefile;
 
-mod kubectl;
+mod kubernetes;
 mod secrets;
 mod util;
 
 use crate::commands::*;
 use crate::error::Result;
 
-fn run() -> Result<()> {
+/// Exit code of `diff` when the cluster differs from the configuration, distinct from failures.
+const EXIT_DIFFERENCES: i32 = 2;
+
+/// Exit code of `deploy --wait` when a workload did not become ready, distinct from failures
+/// to deploy at all.
+const EXIT_NOT_READY: i32 = 3;
+
+fn run() -> Result<i32> {
     let manifest_arg = clap::Arg::with_name("manifest")
         .required(true)
         .value_name("manifest-path")
@@ -59,7 +66,61 @@ fn run() -> Result<()> {
         .long("dry-run")
         .help("Do not actually write the configuration");
 
-    let generate_args = &[dry_run_arg, inventory_arg, manifest_arg.clone()];
+    let prune_arg = clap::Arg::with_name("prune")
+        .long("prune")
+        .help("Delete resources of this project that are no longer in the manifest");
+
+    let server_dry_run_arg = clap::Arg::with_name("server-dry-run")
+        .long("server-dry-run")
+        .conflicts_with("dry-run")
+        .help("Validate the configuration against the cluster without applying it");
+
+    let wait_arg = clap::Arg::with_name("wait")
+        .short("w")
+        .long("wait")
+        .conflicts_with_all(&["dry-run", "server-dry-run"])
+        .help("Wait until all deployed workloads are ready");
+
+    let timeout_arg = clap::Arg::with_name("timeout")
+        .long("timeout")
+        .value_name("seconds")
+        .takes_value(true)
+        .default_value("300")
+        .help("How long to wait for workloads and jobs to become ready");
+
+    let run_job_arg = clap::Arg::with_name("run-job")
+        .long("run-job")
+        .value_name("job")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Run a job of the manifest to completion before deploying the rest");
+
+    let encryption_arg = clap::Arg::with_name("encryption")
+        .short("e")
+        .long("encryption")
+        .value_name("type")
+        .takes_value(true)
+        .possible_values(&["sops", "ansible-vault"])
+        .default_value("ansible-vault")
+        .help("Encryption type (either sops or ansible-vault)");
+
+    let age_arg = clap::Arg::with_name("age")
+        .long("age")
+        .value_name("recipient")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Age recipient to encrypt SOPS files for, instead of the current or own keys");
+
+    let secret_file_arg = clap::Arg::with_name("file")
+        .required(true)
+        .value_name("file")
+        .takes_value(true)
+        .help("Path to secret file");
+
+    let generate_args = &[dry_run_arg, inventory_arg.clone(), manifest_arg.clone()];
+    let cluster_args = &[token_type_arg, token_arg, kubeconfig_arg];
 
     let matches = clap::App::new(clap::crate_name!())
         .about(clap::crate_description!())
@@ -79,11 +140,68 @@ fn run() -> Result<()> {
         .subcommand(
             clap::SubCommand::with_name("deploy")
                 .about("Deploys a configuration to the current cluster")
-                .arg(token_type_arg)
-                .arg(token_arg)
-                .arg(kubeconfig_arg)
+                .arg(prune_arg)
+                .arg(server_dry_run_arg)
+                .arg(wait_arg)
+                .arg(timeout_arg)
+                .arg(run_job_arg)
+                .args(cluster_args)
                 .args(generate_args),
         )
+        .subcommand(
+            clap::SubCommand::with_name("diff")
+                .about("Shows the differences between a configuration and the current cluster")
+                .args(cluster_args)
+                .arg(&inventory_arg)
+                .arg(&manifest_arg),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("secret")
+                .about("Encrypts, views and edits secret files")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("encrypt")
+                        .about("Encrypts a plaintext file in place")
+                        .arg(&encryption_arg)
+                        .arg(&age_arg)
+                        .arg(&secret_file_arg),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("view")
+                        .about("Prints the decrypted content of a file")
+                        .arg(&encryption_arg)
+                        .arg(&secret_file_arg),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("edit")
+                        .about("Opens the decrypted content of a file in $EDITOR")
+                        .arg(&encryption_arg)
+                        .arg(&age_arg)
+                        .arg(&secret_file_arg),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("rekey")
+                        .about("Encrypts files again, with NEW_SECRET or a new SOPS data key")
+                        .arg(&encryption_arg)
+                        .arg(&age_arg)
+                        .arg(
+                            clap::Arg::with_name("manifest")
+                                .short("m")
+                                .long("manifest")
+                                .value_name("manifest-path")
+                                .takes_value(true)
+                                .conflicts_with("file")
+                                .help("Rekey every secret of this manifest"),
+                        )
+                        .arg(&inventory_arg)
+                        .arg(
+                            secret_file_arg
+                                .clone()
+                                .multiple(true)
+                                .required_unless("manifest"),
+                        ),
+                ),
+        )
         .get_matches();
 
     match matches.subcommand() {
@@ -101,28 +219,50 @@ fn run() -> Result<()> {
         }
         ("deploy", Some(opts)) => {
             let project_opts = ProjectOpts::parse(opts);
-            let kubeconfig = clap::value_t!(opts.value_of("kubeconfig"), std::path::PathBuf).ok();
-            let token = clap::value_t!(opts.value_of("token"), std::path::PathBuf);
-            let token_type = clap::value_t!(
-                opts.value_of("token-type"),
-                crate::project::types::EncryptionType
-            );
-            let token_source = match (token_type, token) {
-                (Ok(e), Ok(token)) => Some((e, token)),
-                _ => None,
-            };
-
-            project_deploy::exec(project_opts, kubeconfig, token_source)?
+            let cluster_opts = ClusterOpts::parse(opts);
+            let deploy_opts = project_deploy::DeployOpts::parse(opts);
+
+            project_deploy::exec(project_opts, cluster_opts, deploy_opts)?
+        }
+        ("diff", Some(opts)) => {
+            let project_opts = ProjectOpts::parse(opts);
+            let cluster_opts = ClusterOpts::parse(opts);
+
+            if project_diff::exec(project_opts, cluster_opts)? {
+                return Ok(EXIT_DIFFERENCES);
+            }
+        }
+        ("secret", Some(opts)) => {
+            let (command, opts) = opts.subcommand();
+            let opts = opts.expect("a subcommand is required");
+            let secret_opts = secret::SecretOpts::parse(opts);
+            let file = || clap::value_t_or_exit!(opts.value_of("file"), std::path::PathBuf);
+
+            match command {
+                "encrypt" => secret::encrypt(&file(), &secret_opts)?,
+                "view" => secret::view(&file(), &secret_opts)?,
+                "edit" => secret::edit(&file(), &secret_opts)?,
+                "rekey" => {
+                    let files = if opts.is_present("manifest") {
+                        secret::manifest_files(&ProjectOpts::parse(opts))?
+                    } else {
+                        secret_opts.files(opts)
+                    };
+
+                    secret::rekey(&files, &secret_opts)?
+                }
+                _ => unreachable!("unknown secret subcommand '{}'", command),
+            }
         }
         _ => (),
     }
 
-    Ok(())
+    Ok(0)
 }
 
 fn main() {
     ::std::process::exit(match run() {
-        Ok(_) => 0,
+        Ok(code) => code,
         Err(err) => {
             use failure::AsFail;
             eprintln!("{}", err);
@@ -131,7 +271,24 @@ fn main() {
                 eprintln!("cause: {}", cause);
             }
 
-            1
+            match err.kind() {
+                crate::error::ErrorKind::DryRunError { rejected } => {
+                    for (name, message) in rejected {
+                        eprintln!("{}: {}", name, message);
+                    }
+                }
+                crate::error::ErrorKind::RolloutError { diagnostics, .. } => {
+                    for line in diagnostics {
+                        eprintln!("{}", line);
+                    }
+                }
+                _ => {}
+            }
+
+            match err.kind() {
+                crate::error::ErrorKind::RolloutError { .. } => EXIT_NOT_READY,
+                _ => 1,
+            }
         }
     });
 }
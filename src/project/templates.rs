@@ -2,121 +2,248 @@ use askama::Template;
 
 use crate::project::types::*;
 use crate::resourcefile::{Resourcefile, Resourceproto};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 #[derive(Clone, Copy)]
-pub struct OptRevisionVersion<'a>(pub Option<&'a str>);
-
-impl<'a> OptRevisionVersion<'a> {
-    pub fn is_some(&self) -> bool {
-        self.0.is_some()
-    }
-    pub fn unwrap(self) -> &'a str {
-        self.0.unwrap()
-    }
-}
-
 pub enum IssuerMode {
     Production,
     Staging,
 }
 
+/// A reference to a cert-manager `ClusterIssuer` or namespaced `Issuer`.
+pub struct IssuerRef {
+    pub name: String,
+    pub kind: IssuerKind,
+}
+
 #[derive(Template)]
 #[template(path = "project/certificate.yml")]
 pub struct CertificateTemplate<'a> {
-    resource_version: OptRevisionVersion<'a>,
     name: &'a str,
-    issuer: &'a str,
-    hostnames: &'a Vec<String>,
+    secret_name: &'a str,
+    issuer: &'a IssuerRef,
+    hostnames: Vec<String>,
 }
 
 impl<'a> CertificateTemplate<'a> {
-    /// Issue the certificate for an ingress.
-    ///
-    /// Currently we also support the `dns-01` ACME solver, hence the tight coupling between Ingress and Certificates is
-    /// no longer conceptually required. For practical reasons we however still require it in Kuberwave.
-    ///
-    /// If you require a Certificate without necessarilly having an Ingress, please consider decoupling Ingresses and Certificates.
+    /// Issue a certificate for the hosts of an ingress or of a standalone certificate.
     pub fn instantiate(
-        resource_version: OptRevisionVersion<'a>,
-        ingress: &'a Ingress,
-        mode: IssuerMode,
+        name: &'a str,
+        secret_name: &'a str,
+        hosts: &[String],
+        issuer: &'a IssuerRef,
     ) -> Resourcefile {
         (Resourceproto {
-            name: &format!("certificate-{}.yml", &ingress.certificate),
+            name: &format!("certificate-{}.yml", name),
             prototype: CertificateTemplate {
-                resource_version,
-                name: &ingress.certificate,
-                issuer: match (mode, &ingress.certificate_solver) {
-                    (IssuerMode::Production, CertificateSolver::HTTP) => "kikundi-production-http",
-                    (IssuerMode::Production, CertificateSolver::DNS) => "kikundi-production-dns",
-                    (IssuerMode::Staging, CertificateSolver::HTTP) => "kikundi-staging-http",
-                    (IssuerMode::Staging, CertificateSolver::DNS) => "kikundi-staging-dns",
-                    (IssuerMode::Production, CertificateSolver::NONE) => {
-                        panic!("NONE certificate resolver should not be created")
-                    }
-                    (IssuerMode::Staging, CertificateSolver::NONE) => {
-                        panic!("NONE certificate resolver should not be created")
-                    }
-                },
-                hostnames: &ingress.hosts,
+                name,
+                secret_name,
+                issuer,
+                // Wildcard hosts would otherwise be read as YAML aliases.
+                hostnames: hosts.iter().map(|host| quote(host)).collect(),
             },
         })
         .render()
     }
 }
 
-pub struct IngressService<'a> {
-    name: &'a str,
+pub struct IngressPathSpec<'a> {
+    path: String,
+    path_type: &'static str,
+    service: &'a str,
     port: u16,
 }
 
-pub struct IngressHost<'a> {
-    name: &'a str,
-    service: IngressService<'a>,
+pub struct IngressRule<'a> {
+    host: String,
+    paths: Vec<IngressPathSpec<'a>>,
 }
 
 pub struct Annotation<'a> {
     key: &'a str,
-    value: &'a str,
+    value: String,
 }
 
 #[derive(Template)]
 #[template(path = "project/ingress.yml")]
 pub struct IngressTemplate<'a> {
     name: &'a str,
-    certificate: &'a str,
-    hosts: Vec<IngressHost<'a>>,
+    class: &'a str,
+    tls_secret: &'a str,
+    rules: Vec<IngressRule<'a>>,
     annotations: Vec<Annotation<'a>>,
 }
 
 impl<'a> IngressTemplate<'a> {
-    pub fn instantiate(ingress: &'a Ingress) -> Resourcefile {
+    pub fn instantiate(
+        ingress: &'a Ingress,
+        rules: &[(&'a str, Vec<Backend<'a>>)],
+        tls_secret: &'a str,
+        class: &'a str,
+        annotations: &'a BTreeMap<String, String>,
+    ) -> Resourcefile {
         (Resourceproto {
             name: &format!("ingress-{}.yml", ingress.name),
             prototype: IngressTemplate {
                 name: &ingress.name,
-                certificate: &ingress.certificate,
-                hosts: ingress
-                    .hosts
+                class,
+                tls_secret,
+                rules: rules
+                    .iter()
+                    .map(|(host, backends)| IngressRule {
+                        host: quote(host),
+                        paths: backends
+                            .iter()
+                            .map(|backend| IngressPathSpec {
+                                path: quote(backend.path),
+                                path_type: backend.path_type.as_str(),
+                                service: backend.service,
+                                port: backend.port,
+                            })
+                            .collect(),
+                    })
+                    .collect(),
+                annotations: annotations
+                    .iter()
+                    .map(|(key, value)| Annotation {
+                        key,
+                        value: quote(value),
+                    })
+                    .collect(),
+            },
+        })
+        .render()
+    }
+}
+
+pub enum MiddlewareKind {
+    BodySize(u64),
+    RedirectHttps,
+    BasicAuth(String, String),
+}
+
+/// A Traefik middleware implementing an option of an ingress.
+pub struct Middleware {
+    pub name: String,
+    pub kind: MiddlewareKind,
+}
+
+#[derive(Template)]
+#[template(path = "project/traefik-middlewares.yml")]
+pub struct MiddlewareTemplate<'a> {
+    middlewares: &'a [Middleware],
+}
+
+impl<'a> MiddlewareTemplate<'a> {
+    pub fn instantiate(ingress: &str, middlewares: &'a [Middleware]) -> Resourcefile {
+        (Resourceproto {
+            name: &format!("middlewares-{}.yml", ingress),
+            prototype: MiddlewareTemplate { middlewares },
+        })
+        .render()
+    }
+}
+
+pub struct RouteRule<'a> {
+    path_type: &'static str,
+    path: String,
+    service: &'a str,
+    port: u16,
+}
+
+#[derive(Template)]
+#[template(path = "project/httproute.yml")]
+pub struct HTTPRouteTemplate<'a> {
+    name: &'a str,
+    gateway: &'a Gateway,
+    hostnames: Vec<String>,
+    rules: Vec<RouteRule<'a>>,
+}
+
+impl<'a> HTTPRouteTemplate<'a> {
+    pub fn instantiate(
+        name: &'a str,
+        hosts: &[&str],
+        backends: &'a [(RouteMatch, Backend<'a>)],
+        gateway: &'a Gateway,
+    ) -> Resourcefile {
+        (Resourceproto {
+            name: &format!("httproute-{}.yml", name),
+            prototype: HTTPRouteTemplate {
+                name,
+                gateway,
+                hostnames: hosts.iter().map(|host| quote(host)).collect(),
+                rules: backends
                     .iter()
-                    .map(|host| IngressHost {
-                        name: host,
-                        service: IngressService {
-                            name: &ingress.service,
-                            port: ingress.port,
-                        },
+                    .map(|(route_match, backend)| RouteRule {
+                        path_type: route_match.as_str(),
+                        path: quote(backend.path),
+                        service: backend.service,
+                        port: backend.port,
                     })
                     .collect(),
-                annotations: ingress
-                    .annotations
-                    .as_ref()
-                    .map(|map| {
-                        map.iter()
-                            .map(|(key, value)| Annotation { key, value })
-                            .collect()
+            },
+        })
+        .render()
+    }
+}
+
+pub struct Listener<'a> {
+    name: String,
+    hostname: String,
+    secret: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "project/gateway.yml")]
+pub struct GatewayTemplate<'a> {
+    name: &'a str,
+    class_name: &'a str,
+    listeners: Vec<Listener<'a>>,
+}
+
+impl<'a> GatewayTemplate<'a> {
+    /// A Gateway with an HTTP listener, and an HTTPS listener for every host with its certificate.
+    pub fn instantiate(
+        gateway: &'a Gateway,
+        class_name: &'a str,
+        hosts: &[(&str, &'a str)],
+    ) -> Resourcefile {
+        (Resourceproto {
+            name: &format!("gateway-{}.yml", gateway.name),
+            prototype: GatewayTemplate {
+                name: &gateway.name,
+                class_name,
+                listeners: hosts
+                    .iter()
+                    .enumerate()
+                    .map(|(n, (host, secret))| Listener {
+                        name: format!("https-{}", n),
+                        hostname: quote(host),
+                        secret,
                     })
-                    .unwrap_or_default(),
+                    .collect(),
+            },
+        })
+        .render()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "project/reference-grant.yml")]
+pub struct ReferenceGrantTemplate<'a> {
+    gateway_namespace: &'a str,
+    secrets: &'a [&'a str],
+}
+
+impl<'a> ReferenceGrantTemplate<'a> {
+    /// Allow Gateways in another namespace to use the certificates of this namespace.
+    pub fn instantiate(gateway_namespace: &'a str, secrets: &'a [&'a str]) -> Resourcefile {
+        (Resourceproto {
+            name: "reference-grant-gateway.yml",
+            prototype: ReferenceGrantTemplate {
+                gateway_namespace,
+                secrets,
             },
         })
         .render()
@@ -160,7 +287,7 @@ pub struct SecretOpaqueTemplate<'a> {
 }
 
 impl<'a> SecretOpaqueTemplate<'a> {
-    pub fn instantiate(name: &str, secrets: HashMap<String, Vec<u8>>) -> Resourcefile {
+    pub fn instantiate(name: &str, secrets: BTreeMap<String, Vec<u8>>) -> Resourcefile {
         let secrets: Vec<(String, String)> = secrets
             .into_iter()
             .map(|(k, v)| (k, b64encode(&v)))
@@ -198,6 +325,11 @@ impl<'a> ConfigMapTemplate<'a> {
         data: Vec<(String, String)>,
         binary_data: Vec<(String, Vec<u8>)>,
     ) -> Resourcefile {
+        let data: Vec<(String, String)> = data
+            .into_iter()
+            .map(|(name, value)| (quote(&name), block_scalar(&value, 4)))
+            .collect();
+
         let data: Vec<Field> = data
             .iter()
             .map(|(name, value)| Field { name, value })
@@ -205,7 +337,7 @@ impl<'a> ConfigMapTemplate<'a> {
 
         let binary_data: Vec<(String, String)> = binary_data
             .into_iter()
-            .map(|(name, value)| (name, base64::encode(value)))
+            .map(|(name, value)| (quote(&name), base64::encode(value)))
             .collect();
 
         let binary_data: Vec<Field> = binary_data
@@ -228,24 +360,595 @@ impl<'a> ConfigMapTemplate<'a> {
 #[derive(Template)]
 #[template(path = "project/network-policy.yml")]
 pub struct NetworkPolicyTemplate<'a> {
-    namespace: &'a str,
-    ingresses: &'a Vec<Ingress>,
+    namespace: String,
+    namespace_label: String,
+    ingress_namespace: Vec<(String, String)>,
+    pod_label: String,
+    services: Vec<(String, &'a BTreeSet<u16>)>,
 }
 
 impl<'a> NetworkPolicyTemplate<'a> {
-    pub fn instantiate(namespace: &str, ingress: &Option<Vec<Ingress>>) -> Resourcefile {
-        let empty: Vec<Ingress> = vec![];
-
+    /// Allow traffic within the namespace, and from the ingress controller to the given ports
+    /// of the services behind ingresses.
+    pub fn instantiate(
+        namespace: &str,
+        services: &'a BTreeMap<&str, BTreeSet<u16>>,
+        policy: &NetworkPolicy,
+    ) -> Resourcefile {
         (Resourceproto {
             name: "network-policy.yml",
             prototype: NetworkPolicyTemplate {
-                namespace,
-                ingresses: match ingress {
-                    Some(ingresses) => ingresses,
-                    _ => &empty,
-                },
+                namespace: quote(namespace),
+                namespace_label: quote(policy.namespace_label(true)),
+                ingress_namespace: policy
+                    .ingress_namespace()
+                    .into_iter()
+                    .map(|(key, value)| (quote(key), quote(value)))
+                    .collect(),
+                pod_label: quote(policy.pod_label()),
+                services: services
+                    .iter()
+                    .map(|(service, ports)| (service.to_string(), ports))
+                    .collect(),
+            },
+        })
+        .render()
+    }
+}
+
+pub struct PeerSpec {
+    namespace_label: String,
+    namespace: String,
+    pods: Vec<(String, String)>,
+    ports: Vec<u16>,
+}
+
+impl PeerSpec {
+    fn new(peer: &NamespacePeer, namespace_label: &str) -> PeerSpec {
+        PeerSpec {
+            namespace_label: quote(namespace_label),
+            namespace: quote(&peer.namespace),
+            pods: peer
+                .pods
+                .iter()
+                .flatten()
+                .map(|(key, value)| (quote(key), quote(value)))
+                .collect(),
+            ports: peer.ports.clone(),
+        }
+    }
+}
+
+pub struct CidrSpec {
+    cidr: String,
+    except: Vec<String>,
+    ports: Vec<u16>,
+}
+
+pub struct EgressSpec {
+    dns: bool,
+    dns_namespace: Vec<(String, String)>,
+    dns_pods: Vec<(String, String)>,
+    cidrs: Vec<CidrSpec>,
+    namespaces: Vec<PeerSpec>,
+}
+
+#[derive(Template)]
+#[template(path = "project/network-policy-rules.yml")]
+pub struct NetworkPolicyRulesTemplate<'a> {
+    /// The peers allowed per namespace, which share a policy named after the namespace.
+    allow_from: Vec<(&'a str, Vec<PeerSpec>)>,
+    egress: Option<EgressSpec>,
+}
+
+impl<'a> NetworkPolicyRulesTemplate<'a> {
+    pub fn instantiate(policy: &'a NetworkPolicy) -> Resourcefile {
+        let namespace_label = policy.namespace_label(false);
+
+        (Resourceproto {
+            name: "network-policy-rules.yml",
+            prototype: NetworkPolicyRulesTemplate {
+                allow_from: policy.allow_from.iter().fold(vec![], |mut groups, peer| {
+                    let spec = PeerSpec::new(peer, namespace_label);
+                    match groups
+                        .iter_mut()
+                        .find(|(namespace, _)| *namespace == peer.namespace)
+                    {
+                        Some((_, specs)) => specs.push(spec),
+                        None => groups.push((peer.namespace.as_str(), vec![spec])),
+                    }
+                    groups
+                }),
+                egress: policy.egress.as_ref().map(|egress| EgressSpec {
+                    dns: egress.dns,
+                    dns_namespace: egress
+                        .dns_namespace()
+                        .into_iter()
+                        .map(|(key, value)| (quote(key), quote(value)))
+                        .collect(),
+                    dns_pods: egress
+                        .dns_pods()
+                        .into_iter()
+                        .map(|(key, value)| (quote(key), quote(value)))
+                        .collect(),
+                    cidrs: egress
+                        .cidrs
+                        .iter()
+                        .map(|cidr| CidrSpec {
+                            cidr: quote(&cidr.cidr),
+                            except: cidr.except.iter().map(|e| quote(e)).collect(),
+                            ports: cidr.ports.clone(),
+                        })
+                        .collect(),
+                    namespaces: egress
+                        .namespaces
+                        .iter()
+                        .map(|peer| PeerSpec::new(peer, namespace_label))
+                        .collect(),
+                }),
+            },
+        })
+        .render()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "project/persistent-volume-claim.yml")]
+pub struct PersistentVolumeClaimTemplate<'a> {
+    name: &'a str,
+    size: String,
+    storage_class: Option<&'a str>,
+    access_modes: String,
+}
+
+impl<'a> PersistentVolumeClaimTemplate<'a> {
+    pub fn instantiate(volume: &'a VolumeClaim, storage_class: Option<&'a str>) -> Resourcefile {
+        (Resourceproto {
+            name: &format!("pvc-{}.yml", volume.name),
+            prototype: PersistentVolumeClaimTemplate {
+                name: &volume.name,
+                size: quote(&volume.size),
+                storage_class,
+                access_modes: quote_all(&volume.access_modes),
+            },
+        })
+        .render()
+    }
+}
+
+/// Render a string as a double-quoted YAML scalar.
+pub fn quote(value: &str) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+/// Whether a character can appear in a YAML document as it is. Carriage returns and the Unicode
+/// line separators would be read as line breaks.
+fn is_printable(c: char) -> bool {
+    matches!(c, '\t' | '\n' | ' '..='~')
+        || (c >= '\u{a0}' && !matches!(c, '\u{2028}' | '\u{2029}' | '\u{feff}'))
+}
+
+/// Render multi-line text as a literal block scalar indented by `spaces`, such that it stays
+/// readable in `kubectl describe` and diffs. Text a block scalar cannot represent exactly, such
+/// as trailing spaces, a first line starting with a space or several final line breaks, is
+/// double-quoted instead.
+pub fn block_scalar(value: &str, spaces: usize) -> String {
+    let (body, chomping) = match value.strip_suffix('\n') {
+        Some(body) => (body, ""),
+        None => (value, "-"),
+    };
+
+    let representable = value.contains('\n')
+        && !body.is_empty()
+        && !body.ends_with('\n')
+        && !body.trim_start_matches('\n').starts_with(' ')
+        && body.chars().all(is_printable)
+        && body.split('\n').all(|line| !line.ends_with([' ', '\t']));
+    if !representable {
+        return quote(value);
+    }
+
+    let prefix = " ".repeat(spaces);
+    let lines: Vec<String> = body
+        .split('\n')
+        .map(|line| match line {
+            "" => String::new(),
+            line => format!("{}{}", prefix, line),
+        })
+        .collect();
+
+    format!("|{}\n{}", chomping, lines.join("\n"))
+}
+
+/// Render a list of strings as a YAML flow sequence.
+fn quote_all(values: &[String]) -> String {
+    serde_json::to_string(values).unwrap()
+}
+
+fn indent(text: &str, spaces: usize) -> String {
+    let prefix = " ".repeat(spaces);
+    text.lines()
+        .map(|line| format!("{}{}", prefix, line))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[derive(PartialEq)]
+pub enum VolumeKind {
+    ConfigMap,
+    Secret,
+    Claim,
+}
+
+/// A key of a ConfigMap or Secret projected to a path in the volume.
+#[derive(serde_derive::Serialize, Debug, Clone)]
+pub struct VolumeItem {
+    pub key: String,
+    pub path: String,
+}
+
+impl VolumeItem {
+    fn quoted_key(&self) -> String {
+        quote(&self.key)
+    }
+
+    fn quoted_path(&self) -> String {
+        quote(&self.path)
+    }
+}
+
+/// A ConfigMap, Secret or PersistentVolumeClaim mounted into a container.
+pub struct Volume {
+    pub name: String,
+    pub source: String,
+    pub kind: VolumeKind,
+    pub path: String,
+    /// The layout of the keys in the volume; keys end up as files of the same name if empty.
+    pub items: Vec<VolumeItem>,
+    /// Mount every item separately, leaving the rest of the directory in the image alone.
+    pub sub_path: bool,
+    /// Annotate the pods with this hash of the content, so they are replaced when it changes.
+    pub checksum: Option<String>,
+}
+
+impl Volume {
+    fn mount_path(&self) -> String {
+        quote(&self.path)
+    }
+
+    fn item_mount_path(&self, item: &VolumeItem) -> String {
+        quote(&format!(
+            "{}/{}",
+            self.path.trim_end_matches('/'),
+            item.path
+        ))
+    }
+
+    /// Items only have to be listed when a key is not mounted under its own name.
+    fn has_layout(&self) -> bool {
+        self.items.iter().any(|item| item.key != item.path)
+    }
+
+    /// Only volume claims are writable, hardmounts and secrets come from the manifest.
+    fn read_only(&self) -> bool {
+        self.kind != VolumeKind::Claim
+    }
+}
+
+pub enum EnvVarSource<'a> {
+    Value(String),
+    Secret(&'a str, &'a str),
+}
+
+pub struct EnvVar<'a> {
+    name: &'a str,
+    value: EnvVarSource<'a>,
+}
+
+pub struct ProbeSpec {
+    field: &'static str,
+    command: Option<String>,
+    path: Option<String>,
+    port: u16,
+    initial_delay_seconds: Option<u32>,
+    period_seconds: Option<u32>,
+    failure_threshold: Option<u32>,
+}
+
+impl ProbeSpec {
+    fn new(field: &'static str, probe: &Probe, ports: &[Port]) -> ProbeSpec {
+        ProbeSpec {
+            field,
+            command: probe.command.as_deref().map(quote_all),
+            path: probe.path.as_deref().map(quote),
+            port: probe
+                .port
+                .or_else(|| ports.first().map(Port::container_port))
+                .unwrap_or(80),
+            initial_delay_seconds: probe.initial_delay_seconds,
+            period_seconds: probe.period_seconds,
+            failure_threshold: probe.failure_threshold,
+        }
+    }
+}
+
+/// The pod specification shared by workloads and jobs.
+#[derive(Template)]
+#[template(path = "project/pod-spec.yml")]
+pub struct PodSpecTemplate<'a> {
+    name: &'a str,
+    image: String,
+    command: Option<String>,
+    args: Option<String>,
+    env: Vec<EnvVar<'a>>,
+    ports: &'a [Port],
+    requests: Vec<(String, String)>,
+    limits: Vec<(String, String)>,
+    probes: Vec<ProbeSpec>,
+    volumes: &'a [Volume],
+    image_pull_secrets: Vec<&'a str>,
+    restart_policy: Option<&'a str>,
+}
+
+fn quantities(quantities: Option<&BTreeMap<String, String>>) -> Vec<(String, String)> {
+    quantities
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| (key.clone(), quote(value)))
+        .collect()
+}
+
+impl<'a> PodSpecTemplate<'a> {
+    pub fn render_spec(
+        name: &'a str,
+        container: &'a Container,
+        ports: &'a [Port],
+        probes: Vec<ProbeSpec>,
+        volumes: &'a [Volume],
+        restart_policy: Option<&'a str>,
+    ) -> String {
+        let resources = container.resources.as_ref();
+
+        PodSpecTemplate {
+            name,
+            image: quote(&container.image),
+            command: container.command.as_deref().map(quote_all),
+            args: container.args.as_deref().map(quote_all),
+            env: container
+                .env
+                .iter()
+                .flatten()
+                .map(|(name, value)| EnvVar {
+                    name,
+                    value: match value {
+                        EnvValue::Value(value) => EnvVarSource::Value(quote(value)),
+                        EnvValue::Secret { secret, key } => EnvVarSource::Secret(secret, key),
+                    },
+                })
+                .collect(),
+            ports,
+            requests: quantities(resources.and_then(|r| r.requests.as_ref())),
+            limits: quantities(resources.and_then(|r| r.limits.as_ref())),
+            probes,
+            volumes,
+            image_pull_secrets: container
+                .image_pull_secrets
+                .iter()
+                .flatten()
+                .map(|s| s.as_str())
+                .collect(),
+            restart_policy,
+        }
+        .render()
+        .unwrap()
+    }
+}
+
+/// The annotations with the content hashes of the mounted volumes.
+fn checksum_annotations(volumes: &[Volume]) -> Vec<(String, String)> {
+    volumes
+        .iter()
+        .filter_map(|v| {
+            let checksum = v.checksum.as_ref()?;
+            Some((format!("kuberwave/checksum-{}", v.name), quote(checksum)))
+        })
+        .collect()
+}
+
+#[derive(Template)]
+#[template(path = "project/deployment.yml")]
+pub struct DeploymentTemplate<'a> {
+    name: &'a str,
+    /// Another label with the name, for the network policy.
+    pod_label: Option<String>,
+    replicas: u32,
+    recreate: bool,
+    checksums: Vec<(String, String)>,
+    pod_spec: String,
+}
+
+impl<'a> DeploymentTemplate<'a> {
+    pub fn instantiate(
+        workload: &'a Workload,
+        volumes: &[Volume],
+        pod_label: &str,
+    ) -> Resourcefile {
+        let probes = workload
+            .readiness_probe
+            .iter()
+            .map(|p| ProbeSpec::new("readinessProbe", p, &workload.ports))
+            .chain(
+                workload
+                    .liveness_probe
+                    .iter()
+                    .map(|p| ProbeSpec::new("livenessProbe", p, &workload.ports)),
+            )
+            .collect();
+
+        let pod_spec = PodSpecTemplate::render_spec(
+            &workload.name,
+            &workload.container,
+            &workload.ports,
+            probes,
+            volumes,
+            None,
+        );
+
+        (Resourceproto {
+            name: &format!("deployment-{}.yml", workload.name),
+            prototype: DeploymentTemplate {
+                name: &workload.name,
+                pod_label: Some(pod_label).filter(|label| *label != "app").map(quote),
+                replicas: workload.replicas,
+                // A volume claim can usually only be attached to one node at a time, so the old
+                // pods have to be gone before the new ones can start.
+                recreate: volumes.iter().any(|v| v.kind == VolumeKind::Claim),
+                checksums: checksum_annotations(volumes),
+                pod_spec: indent(&pod_spec, 6),
+            },
+        })
+        .render()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "project/service.yml")]
+pub struct ServiceTemplate<'a> {
+    name: &'a str,
+    ports: &'a [Port],
+}
+
+impl<'a> ServiceTemplate<'a> {
+    pub fn instantiate(workload: &'a Workload) -> Resourcefile {
+        (Resourceproto {
+            name: &format!("service-{}.yml", workload.name),
+            prototype: ServiceTemplate {
+                name: &workload.name,
+                ports: &workload.ports,
+            },
+        })
+        .render()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "project/job-spec.yml")]
+pub struct JobSpecTemplate<'a> {
+    name: &'a str,
+    options: &'a JobOptions,
+    checksums: Vec<(String, String)>,
+    pod_spec: String,
+}
+
+impl<'a> JobSpecTemplate<'a> {
+    pub fn render_spec(
+        name: &'a str,
+        container: &'a Container,
+        options: &'a JobOptions,
+        volumes: &'a [Volume],
+    ) -> String {
+        let pod_spec =
+            PodSpecTemplate::render_spec(name, container, &[], vec![], volumes, Some("Never"));
+
+        JobSpecTemplate {
+            name,
+            options,
+            checksums: checksum_annotations(volumes),
+            pod_spec: indent(&pod_spec, 4),
+        }
+        .render()
+        .unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "project/job.yml")]
+pub struct JobTemplate<'a> {
+    name: &'a str,
+    job_spec: String,
+}
+
+impl<'a> JobTemplate<'a> {
+    pub fn instantiate(job: &'a Job, volumes: &[Volume]) -> Resourcefile {
+        let job_spec =
+            JobSpecTemplate::render_spec(&job.name, &job.container, &job.options, volumes);
+
+        (Resourceproto {
+            name: &format!("job-{}.yml", job.name),
+            prototype: JobTemplate {
+                name: &job.name,
+                job_spec: indent(&job_spec, 2),
             },
         })
         .render()
     }
 }
+
+#[derive(Template)]
+#[template(path = "project/cronjob.yml")]
+pub struct CronJobTemplate<'a> {
+    name: &'a str,
+    schedule: String,
+    concurrency_policy: Option<&'a str>,
+    suspend: Option<bool>,
+    job_spec: String,
+}
+
+impl<'a> CronJobTemplate<'a> {
+    pub fn instantiate(cronjob: &'a CronJob, volumes: &[Volume]) -> Resourcefile {
+        let job_spec = JobSpecTemplate::render_spec(
+            &cronjob.name,
+            &cronjob.container,
+            &cronjob.options,
+            volumes,
+        );
+
+        (Resourceproto {
+            name: &format!("cronjob-{}.yml", cronjob.name),
+            prototype: CronJobTemplate {
+                name: &cronjob.name,
+                schedule: quote(&cronjob.schedule),
+                concurrency_policy: cronjob.concurrency_policy.as_deref(),
+                suspend: cronjob.suspend,
+                job_spec: indent(&job_spec, 6),
+            },
+        })
+        .render()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_scalars_keep_the_text() {
+        let values = [
+            "server {\n    listen 80;\n}\n",
+            "no final line break\nat all",
+            "blank lines\n\n\nin between\n",
+            "\nleading line break\n",
+            "several final line breaks\n\n",
+            " leading space\nsecond line\n",
+            "trailing space \nsecond line\n",
+            "tab\tand unicode: ü €\n",
+            "carriage\r\nreturns\r\n",
+            "single line",
+            "\n",
+            "",
+        ];
+
+        for value in &values {
+            let scalar = block_scalar(value, 2);
+            let document: BTreeMap<String, String> =
+                serde_yaml::from_str(&format!("key: {}\nnext: value\n", scalar)).unwrap();
+            assert_eq!(&document["key"], value, "{}", scalar);
+            assert_eq!(document["next"], "value");
+        }
+
+        assert_eq!(block_scalar("a\nb\n", 2), "|\n  a\n  b");
+        assert_eq!(block_scalar("a\n\nb", 2), "|-\n  a\n\n  b");
+        assert_eq!(block_scalar("trailing \n", 2), quote("trailing \n"));
+        assert_eq!(block_scalar("single line", 2), quote("single line"));
+    }
+}
@@ -1,26 +1,19 @@
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use crate::error::{ErrorKind, Result};
-use crate::kubectl::Kubectl;
+use crate::cluster::types::StorageClass;
+use crate::error::{Error, ErrorKind, Result};
 use crate::project::templates::*;
 use crate::resourcefile::Resourcefile;
 use crate::secrets::read_secret_from_file;
-use failure::ResultExt;
+use failure::{AsFail, ResultExt};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use slugify::slugify;
 use std::collections::HashMap;
 use tera::Context;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
-#[serde(rename_all = "camelCase")]
-pub enum ManifestOptions {
-    #[serde(rename = "pd-ssd")]
-    PdSsd,
-    #[serde(rename = "pd-hdd")]
-    PdHdd,
-}
-
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 #[derive(Default)]
 pub enum EncryptionType {
@@ -97,6 +90,12 @@ pub struct Hardmount {
     name: String,
     dest: String,
     src: String,
+    /// Only take the files matching one of these globs from a directory, e.g. `*.conf`.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Leave out the files and directories matching one of these globs, e.g. `.*`.
+    #[serde(default)]
+    exclude: Vec<String>,
     /// Emit as a Secret instead of a ConfigMap.
     #[serde(default)]
     secret: bool,
@@ -104,24 +103,90 @@ pub struct Hardmount {
     #[serde(default)]
     template: bool,
     /// Optionally remap (copy, not move) file entries in the ConfigMap from something else.
-    /// Keys are the destinations, values are the sources.
+    /// Keys are the destinations, values are the sources, both as paths relative to `src`.
     #[serde(default)]
     remap: Option<HashMap<String, String>>,
+    /// Mount every file on its own with `subPath`, instead of replacing the whole directory.
+    /// Changes to the ConfigMap are then only picked up when the pods restart.
+    #[serde(default, alias = "subPath")]
+    sub_path: bool,
+    /// Put every file in `binaryData` when true, or in `data` when false. By default only files
+    /// that are not UTF-8 text go in `binaryData`. Ignored for secrets.
+    #[serde(default)]
+    binary: Option<bool>,
+    /// Make pods pick up changes to the content, by a hash in the name or a pod annotation.
+    #[serde(default)]
+    hash: Option<HardmountHash>,
+}
+
+/// How changes to the content of a hardmount are propagated to the pods mounting it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HardmountHash {
+    /// Suffix the name of the ConfigMap or Secret with the hash, like kustomize generators do.
+    Suffix,
+    /// Annotate the pods mounting the hardmount with the hash.
+    Annotation,
+}
+
+/// The generated object of a hardmount, available to templates as `hardmounts[<name>]`.
+#[derive(Serialize, Debug, Clone)]
+pub struct HardmountRef {
+    pub name: String,
+    pub checksum: String,
+    /// Where each key goes in the volume, to reproduce the directory tree in `items`.
+    pub items: Vec<VolumeItem>,
 }
 
+/// Label marking objects as generated by kuberwave.
+pub const MANAGED_BY_LABEL: (&str, &str) = ("app.kubernetes.io/managed-by", "kuberwave");
+
+/// Label identifying the project an object was generated for.
+pub const PROJECT_LABEL: &str = "kuberwave/project";
+
+/// Label identifying the manifest an object was generated from, within its project.
+pub const MANIFEST_LABEL: &str = "kuberwave/manifest";
+
+/// The longest value a label may have.
+const MAX_LABEL_LENGTH: usize = 63;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Manifest {
     pub version: u8,
     pub namespace: String,
+    /// Identifies the objects owned by this manifest, defaults to the namespace.
+    pub project: Option<String>,
     pub templates: Option<Vec<PathBuf>>,
-    pub options: Option<HashSet<ManifestOptions>>,
+    /// The storage classes of the cluster used by this project.
+    pub options: Option<HashSet<StorageClass>>,
     #[serde(alias = "defaultNetworkPolicy")]
     pub default_network_policy: Option<bool>,
+    #[serde(alias = "networkPolicy")]
+    pub network_policy: Option<NetworkPolicy>,
     pub ingress: Option<Vec<Ingress>>,
+    /// The default ingress class, defaults to the name of the controller.
+    #[serde(alias = "ingressClass")]
+    pub ingress_class: Option<String>,
+    #[serde(default, alias = "ingressController")]
+    pub ingress_controller: IngressController,
+    /// Whether ingresses are generated as Ingresses or as Gateway API HTTPRoutes.
+    #[serde(default, alias = "ingressApi")]
+    pub ingress_api: IngressApi,
+    pub gateway: Option<Gateway>,
+    pub certificates: Option<Vec<Certificate>>,
+    #[serde(default)]
+    pub issuers: Issuers,
+    pub workloads: Option<Vec<Workload>>,
+    pub jobs: Option<Vec<Job>>,
+    pub cronjobs: Option<Vec<CronJob>>,
+    pub volumes: Option<Vec<VolumeClaim>>,
     pub env: Option<HashSet<String>>,
     pub secrets: Option<Vec<Secret>>,
     pub hardmounts: Option<Vec<Hardmount>>,
+    /// Identifies the manifest among the manifests of its project, see `set_identity`.
+    #[serde(skip)]
+    pub identity: String,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -135,261 +200,2418 @@ pub enum CertificateSolver {
 
 
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Ingress {
-    pub name: String,
-    pub hosts: Vec<String>,
-    pub service: String,
-    pub port: u16,
-    pub certificate: String,
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub enum IssuerKind {
+    #[default]
+    ClusterIssuer,
+    Issuer,
+}
+
+impl IssuerKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IssuerKind::ClusterIssuer => "ClusterIssuer",
+            IssuerKind::Issuer => "Issuer",
+        }
+    }
+}
+
+/// The cert-manager issuers of one solver. Names default to `kikundi-<mode>-<solver>`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SolverIssuers {
+    pub production: Option<String>,
+    pub staging: Option<String>,
+    /// `Issuer` for an issuer in the namespace of the manifest.
     #[serde(default)]
-    pub certificate_solver: CertificateSolver,
-    pub annotations: Option<HashMap<String, String>>,
+    pub kind: IssuerKind,
 }
 
-impl Manifest {
-    pub fn to_sourcefiles(
-        &self,
-        base: &Path,
-        mut context: Context,
-        vaultkey: Option<&str>,
-        kubectl: Option<&Kubectl>,
-    ) -> Result<Vec<Resourcefile>> {
-        let mut files: Vec<Resourcefile> = vec![];
+/// The cert-manager issuers used for certificates, per solver. Use inventory variables to
+/// configure them per environment.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Issuers {
+    /// Use the staging issuers for all certificates, e.g. for test environments.
+    #[serde(default)]
+    pub staging: bool,
+    #[serde(default)]
+    pub http: SolverIssuers,
+    #[serde(default)]
+    pub dns: SolverIssuers,
+}
 
-        match &self.env {
-            Some(es) => {
-                for env in es {
-                    let value = std::env::var(env).context(ErrorKind::EnvError {
-                        name: env.to_string(),
-                    })?;
-                    context.insert(env, &value);
-                }
-            }
-            None => (),
+impl Issuers {
+    /// The issuer mode of a certificate, which can override the default of the manifest.
+    pub fn mode(&self, staging: Option<bool>) -> IssuerMode {
+        if staging.unwrap_or(self.staging) {
+            IssuerMode::Staging
+        } else {
+            IssuerMode::Production
         }
+    }
 
-        match &self.secrets {
-            Some(ss) => {
-                for secret in ss {
-                    secret.apply_context(base, &mut context, vaultkey)?;
-                }
+    /// The issuer for a solver, or `None` if the certificate should not be created.
+    pub fn issuer(&self, solver: &CertificateSolver, mode: IssuerMode) -> Option<IssuerRef> {
+        let (issuers, solver) = match solver {
+            CertificateSolver::HTTP => (&self.http, "http"),
+            CertificateSolver::DNS => (&self.dns, "dns"),
+            CertificateSolver::NONE => return None,
+        };
+        let (name, mode) = match mode {
+            IssuerMode::Production => (&issuers.production, "production"),
+            IssuerMode::Staging => (&issuers.staging, "staging"),
+        };
 
-                for secret in ss {
-                    let sf = secret.to_sourcefile(base, &context, vaultkey);
-                    if let Some(sf) = sf {
-                        files.push(sf)
-                    }
-                }
-            }
-            None => (),
+        Some(IssuerRef {
+            name: name
+                .clone()
+                .unwrap_or_else(|| format!("kikundi-{}-{}", mode, solver)),
+            kind: issuers.kind,
+        })
+    }
+}
+
+/// Pods in another namespace, all of them unless pod labels are given.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NamespacePeer {
+    pub namespace: String,
+    pub pods: Option<BTreeMap<String, String>>,
+    /// The TCP ports traffic is allowed to, all ports if empty.
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+/// An IP range outside of the cluster.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CidrPeer {
+    pub cidr: String,
+    #[serde(default)]
+    pub except: Vec<String>,
+    #[serde(default)]
+    pub ports: Vec<u16>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Restricts outgoing traffic to the namespace itself and the given destinations.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Egress {
+    /// Allow DNS lookups with the cluster DNS.
+    #[serde(default = "default_true")]
+    pub dns: bool,
+    /// The labels of the namespace of the cluster DNS, defaults to that of `kube-system`.
+    pub dns_namespace: Option<BTreeMap<String, String>>,
+    /// The labels of the pods of the cluster DNS, defaults to `k8s-app: kube-dns`.
+    pub dns_pods: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    pub cidrs: Vec<CidrPeer>,
+    #[serde(default)]
+    pub namespaces: Vec<NamespacePeer>,
+}
+
+impl Egress {
+    pub fn dns_namespace(&self) -> Vec<(&str, &str)> {
+        match &self.dns_namespace {
+            Some(labels) => labels
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+            None => vec![("kubernetes.io/metadata.name", "kube-system")],
         }
+    }
 
-        if let Some(true) = &self.default_network_policy {
-            files.push(NetworkPolicyTemplate::instantiate(&self.namespace, &self.ingress));
-        }
-
-        match &self.ingress {
-            Some(is) => {
-                for i in is {
-                    if i.certificate_solver != CertificateSolver::NONE {
-                        let resource_version = kubectl
-                            .map(|kubectl| {
-                                kubectl.get_resource_version("certificate", &i.certificate)
-                            })
-                            .transpose()?
-                            .flatten();
-                        files.push(CertificateTemplate::instantiate(
-                            crate::project::templates::OptRevisionVersion(
-                                resource_version.as_deref(),
-                            ),
-                            i,
-                            IssuerMode::Production,
-                        ));
-                    }
-                    files.push(IngressTemplate::instantiate(i));
-                }
-            }
-            None => (),
-        }
-
-        match &self.templates {
-            Some(ts) => {
-                let template_base = base.join("templates");
-                for template in ts {
-                    let parsed =
-                        crate::util::process_template(&template_base.join(template), &context)?;
-                    files.push(Resourcefile {
-                        name: template.to_string_lossy().to_string(),
-                        buffer: parsed,
-                    })
-                }
-            }
-            None => (),
+    pub fn dns_pods(&self) -> Vec<(&str, &str)> {
+        match &self.dns_pods {
+            Some(labels) => labels
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+            None => vec![("k8s-app", "kube-dns")],
         }
+    }
+}
 
-        match &self.hardmounts {
-            Some(hms) => {
-                for hardmount in hms {
-                    files.push(hardmount.to_sourcefile(base, &context)?)
-                }
-            }
-            None => (),
+/// Network policies in addition to the default network policy.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct NetworkPolicy {
+    /// Allow incoming traffic from other namespaces.
+    #[serde(default)]
+    pub allow_from: Vec<NamespacePeer>,
+    pub egress: Option<Egress>,
+    /// The label holding the name of a namespace.
+    pub namespace_label: Option<String>,
+    /// The labels of the namespace of the ingress controller, defaults to `name: ingress`.
+    pub ingress_namespace: Option<BTreeMap<String, String>>,
+    /// The label holding the name of the service of a pod, defaults to `app`.
+    pub pod_label: Option<String>,
+}
+
+impl NetworkPolicy {
+    /// The label selecting a namespace by name. The default network policy uses `name` for
+    /// backwards compatibility, other rules the label Kubernetes sets itself.
+    pub fn namespace_label(&self, default_policy: bool) -> &str {
+        match &self.namespace_label {
+            Some(label) => label,
+            None if default_policy => "name",
+            None => "kubernetes.io/metadata.name",
         }
+    }
 
-        Ok(files)
+    pub fn ingress_namespace(&self) -> Vec<(&str, &str)> {
+        match &self.ingress_namespace {
+            Some(labels) => labels
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+            None => vec![("name", "ingress")],
+        }
     }
-}
 
-impl RegcredSecret {
-    pub fn to_sourcefile(&self, base: &Path, key: Option<&str>) -> Resourcefile {
-        let content = read_secret_from_file(self.encryption, &base.join(&self.file), key).unwrap();
-        SecretRegcredTemplate::instantiate(&self.name, &content)
+    pub fn pod_label(&self) -> &str {
+        self.pod_label.as_deref().unwrap_or("app")
+    }
+
+    fn has_rules(&self) -> bool {
+        !self.allow_from.is_empty() || self.egress.is_some()
     }
 }
 
-impl OpaqueSecret {
-    pub fn to_sourcefile(&self, base: &Path, key: Option<&str>) -> Resourcefile {
-        let content = read_secret_from_file(self.encryption, &base.join(&self.file), key).unwrap();
-        let secrets: HashMap<String, String> = serde_yaml::from_slice(&content).unwrap();
-        SecretOpaqueTemplate::instantiate(
-            &self.name,
-            secrets
-                .into_iter()
-                .map(|(k, v)| (k, v.into_bytes()))
-                .collect(),
+/// A certificate that is not tied to an ingress, e.g. for a wildcard domain or for TLS
+/// terminated by the workload itself. Ingresses can use it by its name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Certificate {
+    pub name: String,
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub solver: CertificateSolver,
+    /// Overrides the name of the issuer configured for the solver.
+    pub issuer: Option<String>,
+    /// The Secret the certificate is stored in, defaults to the name of the certificate.
+    #[serde(alias = "secretName")]
+    pub secret_name: Option<String>,
+    pub staging: Option<bool>,
+}
+
+impl Certificate {
+    pub fn secret_name(&self) -> &str {
+        self.secret_name.as_deref().unwrap_or(&self.name)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let invalid = |message: String| Err(Error::create(message, ErrorKind::ManifestError));
+
+        if self.hosts.is_empty() {
+            return invalid(format!("Certificate '{}' has no hosts", self.name));
+        }
+        if self.solver == CertificateSolver::NONE {
+            return invalid(format!(
+                "Certificate '{}' can not use the NONE solver",
+                self.name
+            ));
+        }
+        validate_solver(
+            &format!("Certificate '{}'", self.name),
+            &self.hosts,
+            &self.solver,
         )
     }
 }
 
-impl FileSecret {
-    pub fn to_sourcefile(&self, base: &Path, context: &Context, key: Option<&str>) -> Resourcefile {
-        let file = base.join(&self.file);
-        let mut content = read_secret_from_file(self.encryption, &file, key).unwrap();
+/// ACME can only issue wildcard certificates with the DNS solver.
+fn validate_solver(owner: &str, hosts: &[String], solver: &CertificateSolver) -> Result<()> {
+    if *solver == CertificateSolver::HTTP && hosts.iter().any(|h| h.starts_with("*.")) {
+        return Err(Error::create(
+            format!(
+                "{} has a wildcard host, which requires the DNS solver",
+                owner
+            ),
+            ErrorKind::ManifestError,
+        ));
+    }
 
-        if self.template {
-            content = tera::Tera::one_off(&String::from_utf8(content).unwrap(), context, false)
-                .unwrap()
-                .into_bytes()
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub enum PathType {
+    #[default]
+    Prefix,
+    Exact,
+    ImplementationSpecific,
+}
+
+impl PathType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PathType::Prefix => "Prefix",
+            PathType::Exact => "Exact",
+            PathType::ImplementationSpecific => "ImplementationSpecific",
         }
+    }
 
-        SecretOpaqueTemplate::instantiate(
-            &self.name,
-            [(self.dest.to_string(), content)].iter().cloned().collect(),
-        )
+    /// The path match type of an HTTPRoute, which has no implementation specific matching.
+    pub fn route_match(&self) -> Option<RouteMatch> {
+        match self {
+            PathType::Prefix => Some(RouteMatch::PathPrefix),
+            PathType::Exact => Some(RouteMatch::Exact),
+            PathType::ImplementationSpecific => None,
+        }
     }
 }
 
-impl ContextSecret {
-    pub fn apply_context(
-        &self,
-        base: &Path,
-        context: &mut Context,
-        key: Option<&str>,
-    ) -> Result<()> {
-        let content = read_secret_from_file(self.encryption, &base.join(&self.file), key).unwrap();
-        let content = String::from_utf8(content).unwrap();
-        let content = yaml_rust::YamlLoader::load_from_str(&content).unwrap();
+/// The path match type of an HTTPRoute rule.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RouteMatch {
+    PathPrefix,
+    Exact,
+}
 
-        crate::util::append_yaml_to_context(content, context)
+impl RouteMatch {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteMatch::PathPrefix => "PathPrefix",
+            RouteMatch::Exact => "Exact",
+        }
     }
 }
 
-impl Secret {
-    pub fn to_sourcefile(
-        &self,
-        base: &Path,
-        context: &Context,
-        key: Option<&str>,
-    ) -> Option<Resourcefile> {
+fn default_path() -> String {
+    "/".to_owned()
+}
+
+/// A path routed to a service, which defaults to the service of the ingress.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct IngressPath {
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default, alias = "pathType")]
+    pub path_type: PathType,
+    pub service: Option<String>,
+    pub port: Option<u16>,
+}
+
+/// A host of an ingress, optionally with paths of its own instead of those of the ingress.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Host {
+    Name(String),
+    Paths {
+        host: String,
+        paths: Vec<IngressPath>,
+    },
+}
+
+impl Host {
+    pub fn name(&self) -> &str {
         match self {
-            Secret::Opaque(s) => Some(s.to_sourcefile(base, key)),
-            Secret::Regcred(s) => Some(s.to_sourcefile(base, key)),
-            Secret::File(s) => Some(s.to_sourcefile(base, context, key)),
-            Secret::Context(_) => None,
+            Host::Name(name) => name,
+            Host::Paths { host, .. } => host,
         }
     }
+}
 
-    pub fn apply_context(
-        &self,
-        base: &Path,
-        context: &mut Context,
-        key: Option<&str>,
-    ) -> Result<()> {
+/// Hosts sharing the same paths, with the path match type of every path in an HTTPRoute.
+pub type HostGroup<'a> = (Vec<&'a str>, Vec<(RouteMatch, Backend<'a>)>);
+
+/// A path of a host with its service resolved.
+pub struct Backend<'a> {
+    pub path: &'a str,
+    pub path_type: PathType,
+    pub service: &'a str,
+    pub port: u16,
+}
+
+/// HTTP basic authentication for the users in an encrypted YAML file, mapping user names to
+/// passwords.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct IngressAuth {
+    pub file: String,
+    #[serde(default)]
+    pub encryption: EncryptionType,
+    pub realm: Option<String>,
+}
+
+/// The cost of the bcrypt hashes in generated htpasswd files. The ingress controller verifies the
+/// password on every request, so this stays below the default of the bcrypt crate.
+const HTPASSWD_COST: u32 = 10;
+
+const DEFAULT_REALM: &str = "Authentication required";
+
+/// The ingress controller, which determines how ingress options translate to annotations.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IngressController {
+    #[default]
+    Nginx,
+    Traefik,
+}
+
+impl IngressController {
+    pub fn default_class(&self) -> &'static str {
         match self {
-            Secret::Opaque(_) => (),
-            Secret::Regcred(_) => (),
-            Secret::File(_) => (),
-            Secret::Context(s) => s.apply_context(base, context, key)?,
+            IngressController::Nginx => "nginx",
+            IngressController::Traefik => "traefik",
         }
-
-        Ok(())
     }
 }
 
-impl Hardmount {
-    pub fn to_sourcefile(&self, base: &Path, context: &Context) -> Result<Resourcefile> {
-        let path = base.join(&self.src);
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IngressApi {
+    #[default]
+    Ingress,
+    Gateway,
+}
 
-        let files = if path.is_dir() {
-            // We only support direct paths.
-            // TODO recursively iterate over directory.
-            path.read_dir()
-                .map_err(|_| ErrorKind::FileReadError { name: path })?
-                .filter_map(|entry| {
-                    let entry = entry.ok()?;
-                    if entry.metadata().ok()?.is_file() {
-                        Some(entry.path())
-                    } else {
-                        None
-                    }
-                })
-                .collect()
-        } else {
-            vec![path]
-        };
+/// The Gateway that HTTPRoutes attach to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Gateway {
+    pub name: String,
+    /// The namespace of a shared Gateway, defaults to the namespace of the manifest.
+    pub namespace: Option<String>,
+    /// Generate the Gateway in the namespace of the manifest with this GatewayClass, with an
+    /// HTTPS listener for every host.
+    #[serde(alias = "className")]
+    pub class_name: Option<String>,
+}
 
-        let read_f = |path: &Path| {
-            if self.template {
-                crate::util::process_template(path, context).map(|b| b.into_bytes())
-            } else {
-                std::fs::read(path).map_err(|_| {
-                    ErrorKind::FileReadError {
-                        name: path.to_path_buf(),
-                    }
-                    .into()
-                })
-            }
+/// Parse a size in the notation of nginx, e.g. `512k` or `50m`, into bytes.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_lowercase();
+    let (number, unit) = match size.char_indices().last()? {
+        (i, 'k') => (&size[..i], 1 << 10),
+        (i, 'm') => (&size[..i], 1 << 20),
+        (i, 'g') => (&size[..i], 1 << 30),
+        _ => (&size[..], 1),
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ingress {
+    pub name: String,
+    pub hosts: Vec<Host>,
+    /// The service for `/`, and the default for `paths`.
+    pub service: Option<String>,
+    pub port: Option<u16>,
+    /// The paths of every host that does not have paths of its own.
+    pub paths: Option<Vec<IngressPath>>,
+    /// The certificate for the hosts, either one of `certificates` or one generated for this
+    /// ingress with the given name.
+    pub certificate: String,
+    #[serde(default)]
+    pub certificate_solver: CertificateSolver,
+    /// Issue the certificate with the staging issuer, defaults to `issuers.staging`.
+    pub staging: Option<bool>,
+    #[serde(alias = "ingressClass")]
+    pub ingress_class: Option<String>,
+    pub controller: Option<IngressController>,
+    /// The maximum size of a request body, e.g. `50m`.
+    #[serde(alias = "maxBodySize")]
+    pub max_body_size: Option<String>,
+    /// Redirect HTTP to HTTPS, which nginx does by default.
+    #[serde(alias = "sslRedirect")]
+    pub ssl_redirect: Option<bool>,
+    /// A Secret with htpasswd users, under the key `auth` for nginx and `users` for Traefik.
+    #[serde(alias = "basicAuthSecret")]
+    pub basic_auth_secret: Option<String>,
+    /// Basic authentication with users from an encrypted file, instead of `basic_auth_secret`.
+    pub auth: Option<IngressAuth>,
+    /// Additional annotations, which take precedence over those of the controller options.
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl Ingress {
+    pub fn host_names(&self) -> Vec<String> {
+        self.hosts.iter().map(|h| h.name().to_owned()).collect()
+    }
+
+    /// The backend of a path, or of `/` if there is no path.
+    fn backend<'a>(&'a self, path: Option<&'a IngressPath>) -> Result<Backend<'a>> {
+        let service = path
+            .and_then(|p| p.service.as_ref())
+            .or(self.service.as_ref());
+        let port = path.and_then(|p| p.port).or(self.port);
+
+        match (service, port) {
+            (Some(service), Some(port)) => Ok(Backend {
+                path: path.map_or("/", |p| &p.path),
+                path_type: path.map(|p| p.path_type).unwrap_or_default(),
+                service,
+                port,
+            }),
+            _ => Err(Error::create(
+                format!(
+                    "Path '{}' of ingress '{}' has no service and port",
+                    path.map_or("/", |p| &p.path),
+                    self.name
+                ),
+                ErrorKind::ManifestError,
+            )),
+        }
+    }
+
+    /// The Secret with the users allowed to access the ingress and the realm, if any.
+    fn basic_auth(&self) -> Result<Option<(String, &str)>> {
+        match (&self.basic_auth_secret, &self.auth) {
+            (Some(_), Some(_)) => Err(Error::create(
+                format!(
+                    "Ingress '{}' can not have both basicAuthSecret and auth",
+                    self.name
+                ),
+                ErrorKind::ManifestError,
+            )),
+            (Some(secret), None) => Ok(Some((secret.clone(), DEFAULT_REALM))),
+            (None, Some(auth)) => Ok(Some((
+                format!("{}-basic-auth", self.name),
+                auth.realm.as_deref().unwrap_or(DEFAULT_REALM),
+            ))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The htpasswd Secret for the users of the `auth` file, for both nginx and Traefik.
+    ///
+    /// The salts are derived from the name of the Secret and the user rather than random, such
+    /// that the generated Secret is stable for `diff` and only changes when a password does.
+    pub fn auth_secret(&self, base: &Path, key: Option<&str>) -> Result<Option<Resourcefile>> {
+        let (auth, (secret, _)) = match (&self.auth, self.basic_auth()?) {
+            (Some(auth), Some(basic_auth)) => (auth, basic_auth),
+            _ => return Ok(None),
         };
 
-        let file_content: Result<Vec<(String, Vec<u8>)>> = files
-            .into_iter()
-            .map(|path| {
-                Ok((
-                    path.file_name().unwrap().to_str().unwrap().to_string(),
-                    read_f(&path)?,
-                ))
-            })
-            .collect();
+        let file = base.join(&auth.file);
+        let content = read_secret_from_file(auth.encryption, &file, key)?;
+        let users: BTreeMap<String, String> =
+            serde_yaml::from_slice(&content).context(ErrorKind::ParseError { name: file })?;
 
-        let mut file_content: HashMap<String, Vec<u8>> = file_content?.into_iter().collect();
+        let mut htpasswd = String::new();
+        for (user, password) in &users {
+            let digest = ring::digest::digest(
+                &ring::digest::SHA256,
+                format!("{}\0{}", secret, user).as_bytes(),
+            );
+            let mut salt = [0; 16];
+            salt.copy_from_slice(&digest.as_ref()[..16]);
 
-        if let Some(remap) = &self.remap {
-            for (dst, src) in remap {
-                let new_v = file_content
-                    .get(src)
-                    .ok_or(ErrorKind::ManifestError)?
-                    .clone();
-                file_content.insert(dst.clone(), new_v);
-            }
+            let hash = bcrypt::hash_with_salt(password, HTPASSWD_COST, salt)
+                .context(ErrorKind::ManifestError)?
+                .format_for_version(bcrypt::Version::TwoY);
+            htpasswd.push_str(&format!("{}:{}\n", user, hash));
         }
 
-        let result = if self.secret {
-            SecretOpaqueTemplate::instantiate(&self.name, file_content)
-        } else {
-            ConfigMapTemplate::instantiate(&self.name, vec![], file_content.into_iter().collect())
+        Ok(Some(SecretOpaqueTemplate::instantiate(
+            &secret,
+            [
+                ("auth".to_owned(), htpasswd.clone().into_bytes()),
+                ("users".to_owned(), htpasswd.into_bytes()),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+        )))
+    }
+
+    /// The annotations configuring the controller, and the Traefik middlewares they refer to.
+    pub fn controller_settings(
+        &self,
+        controller: IngressController,
+        namespace: &str,
+    ) -> Result<(BTreeMap<String, String>, Vec<Middleware>)> {
+        let mut annotations = BTreeMap::new();
+        let mut middlewares = vec![];
+
+        let body_size = match &self.max_body_size {
+            Some(size) => Some((
+                size,
+                parse_size(size).ok_or_else(|| {
+                    Error::create(
+                        format!("Invalid maxBodySize '{}' of ingress '{}'", size, self.name),
+                        ErrorKind::ManifestError,
+                    )
+                })?,
+            )),
+            None => None,
         };
 
-        Ok(result)
+        let basic_auth = self.basic_auth()?;
+
+        match controller {
+            IngressController::Nginx => {
+                let nginx = |key: &str| format!("nginx.ingress.kubernetes.io/{}", key);
+
+                if let Some((size, _)) = body_size {
+                    annotations.insert(nginx("proxy-body-size"), size.clone());
+                }
+                if let Some(redirect) = self.ssl_redirect {
+                    annotations.insert(nginx("ssl-redirect"), redirect.to_string());
+                }
+                if let Some((secret, realm)) = &basic_auth {
+                    annotations.insert(nginx("auth-type"), "basic".to_owned());
+                    annotations.insert(nginx("auth-secret"), secret.clone());
+                    annotations.insert(nginx("auth-realm"), realm.to_string());
+                }
+            }
+            IngressController::Traefik => {
+                if let Some((_, bytes)) = body_size {
+                    middlewares.push(Middleware {
+                        name: format!("{}-body-size", self.name),
+                        kind: MiddlewareKind::BodySize(bytes),
+                    });
+                }
+                if let Some(true) = self.ssl_redirect {
+                    middlewares.push(Middleware {
+                        name: format!("{}-redirect-https", self.name),
+                        kind: MiddlewareKind::RedirectHttps,
+                    });
+                }
+                if let Some((secret, realm)) = basic_auth {
+                    middlewares.push(Middleware {
+                        name: format!("{}-basic-auth", self.name),
+                        kind: MiddlewareKind::BasicAuth(secret, quote(realm)),
+                    });
+                }
+
+                if !middlewares.is_empty() {
+                    annotations.insert(
+                        "traefik.ingress.kubernetes.io/router.middlewares".to_owned(),
+                        middlewares
+                            .iter()
+                            .map(|m| format!("{}-{}@kubernetescrd", namespace, m.name))
+                            .collect::<Vec<String>>()
+                            .join(","),
+                    );
+                }
+            }
+        }
+
+        for (key, value) in self.annotations.iter().flatten() {
+            annotations.insert(key.clone(), value.clone());
+        }
+
+        Ok((annotations, middlewares))
+    }
+
+    /// The hosts grouped by their paths, as an HTTPRoute applies all its rules to all its
+    /// hostnames.
+    pub fn route_groups(&self) -> Result<Vec<HostGroup<'_>>> {
+        let unsupported = |option: &str| {
+            Err(Error::create(
+                format!(
+                    "Ingress '{}' uses {}, which is not supported with the Gateway API",
+                    self.name, option
+                ),
+                ErrorKind::ManifestError,
+            ))
+        };
+        if self.max_body_size.is_some() {
+            return unsupported("maxBodySize");
+        }
+        if self.ssl_redirect.is_some() {
+            return unsupported("sslRedirect");
+        }
+        if self.basic_auth()?.is_some() {
+            return unsupported("basic authentication");
+        }
+
+        let mut groups: Vec<HostGroup> = vec![];
+        let mut shared: Option<usize> = None;
+        for (host, (name, backends)) in self.hosts.iter().zip(self.rules()?) {
+            let backends = match backends
+                .into_iter()
+                .map(|b| b.path_type.route_match().map(|m| (m, b)))
+                .collect::<Option<Vec<_>>>()
+            {
+                Some(backends) => backends,
+                None => return unsupported("pathType ImplementationSpecific"),
+            };
+
+            match (host, shared) {
+                (Host::Name(_), Some(i)) => groups[i].0.push(name),
+                (Host::Name(_), None) => {
+                    shared = Some(groups.len());
+                    groups.push((vec![name], backends));
+                }
+                (Host::Paths { .. }, _) => groups.push((vec![name], backends)),
+            }
+        }
+
+        Ok(groups)
+    }
+
+    /// The paths of every host, routing `/` to the service of the ingress by default.
+    pub fn rules(&self) -> Result<Vec<(&str, Vec<Backend<'_>>)>> {
+        self.hosts
+            .iter()
+            .map(|host| {
+                let paths = match host {
+                    Host::Name(_) => self.paths.as_ref(),
+                    Host::Paths { paths, .. } => Some(paths),
+                };
+                let backends = match paths {
+                    Some(paths) => paths
+                        .iter()
+                        .map(|path| self.backend(Some(path)))
+                        .collect::<Result<Vec<Backend>>>()?,
+                    None => vec![self.backend(None)?],
+                };
+
+                Ok((host.name(), backends))
+            })
+            .collect()
+    }
+}
+
+/// An environment variable, either a literal value or a key of a secret.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum EnvValue {
+    Value(String),
+    Secret { secret: String, key: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Resources {
+    pub requests: Option<BTreeMap<String, String>>,
+    pub limits: Option<BTreeMap<String, String>>,
+}
+
+/// A hardmount or secret of this manifest mounted into a container by name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Mount {
+    /// Mounted at the `dest` of the hardmount, unless another path is given.
+    Hardmount {
+        hardmount: String,
+        path: Option<String>,
+    },
+    Secret {
+        secret: String,
+        path: String,
+    },
+    /// A volume claim, mounted writable.
+    Volume {
+        volume: String,
+        path: String,
+    },
+}
+
+/// A health check: a command if given, otherwise an HTTP GET if a path is given, otherwise a
+/// TCP connection. The port defaults to the first port of the workload.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Probe {
+    pub command: Option<Vec<String>>,
+    pub path: Option<String>,
+    pub port: Option<u16>,
+    pub initial_delay_seconds: Option<u32>,
+    pub period_seconds: Option<u32>,
+    pub failure_threshold: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Port {
+    pub name: Option<String>,
+    /// The port of the Service.
+    pub port: u16,
+    /// The port the container listens on, defaults to `port`.
+    pub container_port: Option<u16>,
+}
+
+impl Port {
+    pub fn container_port(&self) -> u16 {
+        self.container_port.unwrap_or(self.port)
+    }
+}
+
+/// The container of a workload or job.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Container {
+    pub image: String,
+    pub command: Option<Vec<String>>,
+    pub args: Option<Vec<String>>,
+    pub env: Option<BTreeMap<String, EnvValue>>,
+    pub resources: Option<Resources>,
+    pub mounts: Option<Vec<Mount>>,
+    pub image_pull_secrets: Option<Vec<String>>,
+}
+
+fn default_replicas() -> u32 {
+    1
+}
+
+/// A Deployment, with a Service of the same name if it has ports.
+///
+/// Its pods are labelled with `app: <name>`, and with the pod label of the network policy.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Workload {
+    pub name: String,
+    #[serde(default = "default_replicas")]
+    pub replicas: u32,
+    #[serde(flatten)]
+    pub container: Container,
+    #[serde(default)]
+    pub ports: Vec<Port>,
+    pub readiness_probe: Option<Probe>,
+    pub liveness_probe: Option<Probe>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct JobOptions {
+    pub backoff_limit: Option<u32>,
+    pub active_deadline_seconds: Option<u32>,
+    pub ttl_seconds_after_finished: Option<u32>,
+}
+
+/// A Job running a container to completion, for example a migration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub name: String,
+    #[serde(flatten)]
+    pub container: Container,
+    #[serde(flatten)]
+    pub options: JobOptions,
+}
+
+/// A CronJob running a container on a schedule.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CronJob {
+    pub name: String,
+    pub schedule: String,
+    pub concurrency_policy: Option<String>,
+    pub suspend: Option<bool>,
+    #[serde(flatten)]
+    pub container: Container,
+    #[serde(flatten)]
+    pub options: JobOptions,
+}
+
+fn default_access_modes() -> Vec<String> {
+    vec!["ReadWriteOnce".to_owned()]
+}
+
+/// A PersistentVolumeClaim, provisioned by a storage class enabled in `options` or by the
+/// default storage class of the cluster.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeClaim {
+    pub name: String,
+    pub size: String,
+    pub storage_class: Option<String>,
+    #[serde(default = "default_access_modes")]
+    pub access_modes: Vec<String>,
+}
+
+impl Manifest {
+    pub fn project(&self) -> &str {
+        self.project.as_deref().unwrap_or(&self.namespace)
+    }
+
+    /// Identify the manifest by the name of its file and of the directory it is in, such that
+    /// manifests deploying to the same namespace do not prune each other's objects.
+    pub fn set_identity(&mut self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        let directory = path
+            .parent()
+            .and_then(Path::file_name)
+            .map(|d| d.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let name = path
+            .file_stem()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let mut identity = slugify!(&format!("{} {}", directory, name));
+        identity.truncate(MAX_LABEL_LENGTH);
+        self.identity = identity.trim_end_matches('-').to_owned();
+    }
+
+    /// The labels stamped on every generated object, used to find them again when pruning.
+    pub fn ownership_labels(&self) -> Vec<(&str, &str)> {
+        let mut labels = vec![MANAGED_BY_LABEL, (PROJECT_LABEL, self.project())];
+        if !self.identity.is_empty() {
+            labels.push((MANIFEST_LABEL, &self.identity));
+        }
+
+        labels
+    }
+
+    /// A label selector matching every object owned by this manifest.
+    pub fn ownership_selector(&self) -> String {
+        self.ownership_labels()
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+
+    /// Every encrypted file the manifest refers to, relative to the manifest, in order of
+    /// appearance and without duplicates.
+    pub fn secret_files(&self) -> Vec<(EncryptionType, &str)> {
+        let secrets = self.secrets.iter().flatten().map(Secret::file);
+        let auths = self
+            .ingress
+            .iter()
+            .flatten()
+            .filter_map(|i| i.auth.as_ref())
+            .map(|a| (a.encryption, a.file.as_str()));
+
+        let mut files: Vec<(EncryptionType, &str)> = vec![];
+        for (encryption, file) in secrets.chain(auths) {
+            if !files.iter().any(|(_, f)| *f == file) {
+                files.push((encryption, file));
+            }
+        }
+
+        files
+    }
+
+    /// Look up the hardmounts and secrets a container mounts by name.
+    pub fn resolve_mounts(
+        &self,
+        container: &Container,
+        hardmounts: &BTreeMap<String, HardmountRef>,
+    ) -> Result<Vec<Volume>> {
+        let mounts = match &container.mounts {
+            Some(mounts) => mounts,
+            None => return Ok(vec![]),
+        };
+
+        mounts
+            .iter()
+            .map(|mount| match mount {
+                Mount::Hardmount { hardmount, path } => {
+                    let hm = self
+                        .hardmounts
+                        .iter()
+                        .flatten()
+                        .find(|hm| &hm.name == hardmount)
+                        .ok_or_else(|| {
+                            Error::create(
+                                format!("Unknown hardmount '{}'", hardmount),
+                                ErrorKind::ManifestError,
+                            )
+                        })?;
+
+                    let reference = hardmounts.get(&hm.name).ok_or_else(|| {
+                        Error::create(
+                            format!("Hardmount '{}' was not generated", hm.name),
+                            ErrorKind::ManifestError,
+                        )
+                    })?;
+
+                    Ok(Volume {
+                        name: hm.name.clone(),
+                        source: reference.name.clone(),
+                        kind: if hm.secret {
+                            VolumeKind::Secret
+                        } else {
+                            VolumeKind::ConfigMap
+                        },
+                        path: path.clone().unwrap_or_else(|| hm.dest.clone()),
+                        items: reference.items.clone(),
+                        sub_path: hm.sub_path,
+                        checksum: Some(reference.checksum.clone())
+                            .filter(|_| hm.hash == Some(HardmountHash::Annotation)),
+                    })
+                }
+                Mount::Secret { secret, path } => {
+                    if !self
+                        .secrets
+                        .iter()
+                        .flatten()
+                        .any(|s| s.name() == Some(secret.as_str()))
+                    {
+                        return Err(Error::create(
+                            format!("Unknown secret '{}'", secret),
+                            ErrorKind::ManifestError,
+                        ));
+                    }
+
+                    Ok(Volume {
+                        name: secret.clone(),
+                        source: secret.clone(),
+                        kind: VolumeKind::Secret,
+                        path: path.clone(),
+                        items: vec![],
+                        sub_path: false,
+                        checksum: None,
+                    })
+                }
+                Mount::Volume { volume, path } => {
+                    if !self.volumes.iter().flatten().any(|v| &v.name == volume) {
+                        return Err(Error::create(
+                            format!("Unknown volume '{}'", volume),
+                            ErrorKind::ManifestError,
+                        ));
+                    }
+
+                    Ok(Volume {
+                        name: volume.clone(),
+                        source: volume.clone(),
+                        kind: VolumeKind::Claim,
+                        path: path.clone(),
+                        items: vec![],
+                        sub_path: false,
+                        checksum: None,
+                    })
+                }
+            })
+            .collect()
+    }
+
+    fn gateway(&self) -> Result<&Gateway> {
+        self.gateway.as_ref().ok_or_else(|| {
+            Error::create(
+                "The Gateway API requires a gateway".to_owned(),
+                ErrorKind::ManifestError,
+            )
+        })
+    }
+
+    /// The storage class of a volume claim. Without an explicit class the claim is provisioned
+    /// on the only storage class in `options`, or else by the cluster default.
+    fn storage_class<'a>(&'a self, volume: &'a VolumeClaim) -> Result<Option<&'a str>> {
+        let mut enabled: Vec<&str> = self
+            .options
+            .iter()
+            .flatten()
+            .map(StorageClass::name)
+            .collect();
+        enabled.sort_unstable();
+
+        match &volume.storage_class {
+            Some(class) => {
+                let generated = StorageClass::ALL.iter().any(|c| c.name() == class);
+                if generated && !enabled.contains(&class.as_str()) {
+                    return Err(Error::create(
+                        format!(
+                            "Volume '{}' uses storage class '{}', which is not enabled in options",
+                            volume.name, class
+                        ),
+                        ErrorKind::ManifestError,
+                    ));
+                }
+                Ok(Some(class))
+            }
+            None if enabled.len() == 1 => Ok(Some(enabled[0])),
+            None => Ok(None),
+        }
+    }
+
+    pub fn to_sourcefiles(
+        &self,
+        base: &Path,
+        mut context: Context,
+        vaultkey: Option<&str>,
+    ) -> Result<Vec<Resourcefile>> {
+        let mut files: Vec<Resourcefile> = vec![];
+
+        if let Some(es) = &self.env {
+            for env in es {
+                let value = std::env::var(env).context(ErrorKind::EnvError {
+                    name: env.to_string(),
+                })?;
+                context.insert(env, &value);
+            }
+        }
+
+        if let Some(ss) = &self.secrets {
+            for secret in ss {
+                secret.apply_context(base, &mut context, vaultkey)?;
+            }
+
+            for secret in ss {
+                let sf = secret.to_sourcefile(base, &context, vaultkey)?;
+                if let Some(sf) = sf {
+                    files.push(sf)
+                }
+            }
+        }
+
+        let network_policy = self.network_policy.clone().unwrap_or_default();
+
+        if let Some(true) = &self.default_network_policy {
+            let mut services: BTreeMap<&str, BTreeSet<u16>> = BTreeMap::new();
+            for i in self.ingress.iter().flatten() {
+                for (_, backends) in i.rules()? {
+                    for backend in backends {
+                        services
+                            .entry(backend.service)
+                            .or_default()
+                            .insert(backend.port);
+                    }
+                }
+            }
+
+            files.push(NetworkPolicyTemplate::instantiate(
+                &self.namespace,
+                &services,
+                &network_policy,
+            ));
+        }
+
+        if let Some(cs) = &self.certificates {
+            for c in cs {
+                c.validate()?;
+
+                let mut issuer = self
+                    .issuers
+                    .issuer(&c.solver, self.issuers.mode(c.staging))
+                    .unwrap();
+                if let Some(name) = &c.issuer {
+                    issuer.name = name.clone();
+                }
+                files.push(CertificateTemplate::instantiate(
+                    &c.name,
+                    c.secret_name(),
+                    &c.hosts,
+                    &issuer,
+                ));
+            }
+        }
+
+        if network_policy.has_rules() {
+            files.push(NetworkPolicyRulesTemplate::instantiate(&network_policy));
+        }
+
+        let mut listeners: Vec<(&str, &str)> = vec![];
+        if let Some(is) = &self.ingress {
+            for i in is {
+                let standalone = self
+                    .certificates
+                    .iter()
+                    .flatten()
+                    .find(|c| c.name == i.certificate);
+
+                let tls_secret = match standalone {
+                    Some(c) => c.secret_name(),
+                    None => {
+                        if let Some(issuer) = self
+                            .issuers
+                            .issuer(&i.certificate_solver, self.issuers.mode(i.staging))
+                        {
+                            let hosts = i.host_names();
+                            validate_solver(
+                                &format!("Ingress '{}'", i.name),
+                                &hosts,
+                                &i.certificate_solver,
+                            )?;
+                            files.push(CertificateTemplate::instantiate(
+                                &i.certificate,
+                                &i.certificate,
+                                &hosts,
+                                &issuer,
+                            ));
+                        }
+                        &i.certificate
+                    }
+                };
+
+                if let Some(secret) = i.auth_secret(base, vaultkey)? {
+                    files.push(secret);
+                }
+
+                if self.ingress_api == IngressApi::Gateway {
+                    let gateway = self.gateway()?;
+                    for (n, (hosts, backends)) in i.route_groups()?.iter().enumerate() {
+                        let name = match n {
+                            0 => i.name.clone(),
+                            n => format!("{}-{}", i.name, n),
+                        };
+                        files.push(HTTPRouteTemplate::instantiate(
+                            &name, hosts, backends, gateway,
+                        ));
+                    }
+                    for host in &i.hosts {
+                        if !listeners.iter().any(|(h, _)| *h == host.name()) {
+                            listeners.push((host.name(), tls_secret));
+                        }
+                    }
+                    continue;
+                }
+
+                let controller = i.controller.unwrap_or(self.ingress_controller);
+                let class = i
+                    .ingress_class
+                    .as_deref()
+                    .or(self.ingress_class.as_deref())
+                    .unwrap_or_else(|| controller.default_class());
+                let (annotations, middlewares) =
+                    i.controller_settings(controller, &self.namespace)?;
+
+                if !middlewares.is_empty() {
+                    files.push(MiddlewareTemplate::instantiate(&i.name, &middlewares));
+                }
+                files.push(IngressTemplate::instantiate(
+                    i,
+                    &i.rules()?,
+                    tls_secret,
+                    class,
+                    &annotations,
+                ));
+            }
+        }
+
+        if self.ingress_api == IngressApi::Gateway {
+            let gateway = self.gateway()?;
+            let namespace = gateway.namespace.as_deref().unwrap_or(&self.namespace);
+
+            if let Some(class_name) = &gateway.class_name {
+                if class_name.trim().is_empty() {
+                    return Err(Error::create(
+                        format!("Gateway '{}' has an empty className", gateway.name),
+                        ErrorKind::ManifestError,
+                    ));
+                }
+                if namespace != self.namespace {
+                    return Err(Error::create(
+                        format!(
+                            "Gateway '{}' with a className is generated in namespace '{}'",
+                            gateway.name, self.namespace
+                        ),
+                        ErrorKind::ManifestError,
+                    ));
+                }
+                files.push(GatewayTemplate::instantiate(
+                    gateway, class_name, &listeners,
+                ));
+            } else if namespace != self.namespace && !listeners.is_empty() {
+                // Allow the listeners of the shared Gateway to use our certificates.
+                let mut secrets: Vec<&str> = listeners.iter().map(|(_, s)| *s).collect();
+                secrets.sort_unstable();
+                secrets.dedup();
+                files.push(ReferenceGrantTemplate::instantiate(namespace, &secrets));
+            }
+        }
+
+        if let Some(vs) = &self.volumes {
+            for volume in vs {
+                let storage_class = self.storage_class(volume)?;
+                files.push(PersistentVolumeClaimTemplate::instantiate(
+                    volume,
+                    storage_class,
+                ));
+            }
+        }
+
+        // Report the problems of every hardmount before failing.
+        let mut hardmounts = BTreeMap::new();
+        let mut invalid = vec![];
+        if let Some(hms) = &self.hardmounts {
+            for hardmount in hms {
+                match hardmount.to_sourcefile(base, &context) {
+                    Ok((file, reference)) => {
+                        files.push(file);
+                        hardmounts.insert(hardmount.name.clone(), reference);
+                    }
+                    Err(e) if matches!(e.kind(), ErrorKind::HardmountError { .. }) => {
+                        let problems = e.as_fail().iter_causes().map(|c| c.to_string());
+                        invalid.push(e.to_string());
+                        invalid.extend(problems.flat_map(|p| {
+                            p.lines()
+                                .map(|line| format!("  {}", line))
+                                .collect::<Vec<_>>()
+                        }));
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        if !invalid.is_empty() {
+            return Err(Error::create(
+                format!("Hardmounts are invalid:\n{}", invalid.join("\n")),
+                ErrorKind::ManifestError,
+            ));
+        }
+        context.insert("hardmounts", &hardmounts);
+
+        if let Some(ws) = &self.workloads {
+            for workload in ws {
+                let volumes = self.resolve_mounts(&workload.container, &hardmounts)?;
+                files.push(DeploymentTemplate::instantiate(
+                    workload,
+                    &volumes,
+                    network_policy.pod_label(),
+                ));
+                if !workload.ports.is_empty() {
+                    files.push(ServiceTemplate::instantiate(workload));
+                }
+            }
+        }
+
+        if let Some(js) = &self.jobs {
+            for job in js {
+                let volumes = self.resolve_mounts(&job.container, &hardmounts)?;
+                files.push(JobTemplate::instantiate(job, &volumes));
+            }
+        }
+
+        if let Some(cjs) = &self.cronjobs {
+            for cronjob in cjs {
+                let volumes = self.resolve_mounts(&cronjob.container, &hardmounts)?;
+                files.push(CronJobTemplate::instantiate(cronjob, &volumes));
+            }
+        }
+
+        if let Some(ts) = &self.templates {
+            let template_base = base.join("templates");
+            for template in ts {
+                let parsed =
+                    crate::util::process_template(&template_base.join(template), &context)?;
+                files.push(Resourcefile {
+                    name: template.to_string_lossy().to_string(),
+                    buffer: parsed,
+                    labels: vec![],
+                })
+            }
+        }
+
+        let labels = self.ownership_labels();
+        for file in &mut files {
+            file.add_labels(&labels);
+        }
+
+        Ok(files)
+    }
+}
+
+impl RegcredSecret {
+    pub fn to_sourcefile(&self, base: &Path, key: Option<&str>) -> Result<Resourcefile> {
+        let content = read_secret_from_file(self.encryption, &base.join(&self.file), key)?;
+        Ok(SecretRegcredTemplate::instantiate(&self.name, &content))
+    }
+}
+
+impl OpaqueSecret {
+    pub fn to_sourcefile(&self, base: &Path, key: Option<&str>) -> Result<Resourcefile> {
+        let content = read_secret_from_file(self.encryption, &base.join(&self.file), key)?;
+        let secrets: HashMap<String, String> = serde_yaml::from_slice(&content).unwrap();
+        Ok(SecretOpaqueTemplate::instantiate(
+            &self.name,
+            secrets
+                .into_iter()
+                .map(|(k, v)| (k, v.into_bytes()))
+                .collect(),
+        ))
+    }
+}
+
+impl FileSecret {
+    pub fn to_sourcefile(
+        &self,
+        base: &Path,
+        context: &Context,
+        key: Option<&str>,
+    ) -> Result<Resourcefile> {
+        let file = base.join(&self.file);
+        let mut content = read_secret_from_file(self.encryption, &file, key)?;
+
+        if self.template {
+            content = tera::Tera::one_off(&String::from_utf8(content).unwrap(), context, false)
+                .unwrap()
+                .into_bytes()
+        }
+
+        Ok(SecretOpaqueTemplate::instantiate(
+            &self.name,
+            [(self.dest.to_string(), content)].iter().cloned().collect(),
+        ))
+    }
+}
+
+impl ContextSecret {
+    pub fn apply_context(
+        &self,
+        base: &Path,
+        context: &mut Context,
+        key: Option<&str>,
+    ) -> Result<()> {
+        let content = read_secret_from_file(self.encryption, &base.join(&self.file), key)?;
+        let content = String::from_utf8(content).unwrap();
+        let content = yaml_rust::YamlLoader::load_from_str(&content).unwrap();
+
+        crate::util::append_yaml_to_context(content, context)
+    }
+}
+
+impl Secret {
+    /// The name of the generated Secret, if any.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Secret::Opaque(s) => Some(&s.name),
+            Secret::Regcred(s) => Some(&s.name),
+            Secret::File(s) => Some(&s.name),
+            Secret::Context(_) => None,
+        }
+    }
+
+    /// The encrypted file of this secret, relative to the manifest.
+    pub fn file(&self) -> (EncryptionType, &str) {
+        match self {
+            Secret::Opaque(s) => (s.encryption, &s.file),
+            Secret::Regcred(s) => (s.encryption, &s.file),
+            Secret::File(s) => (s.encryption, &s.file),
+            Secret::Context(s) => (s.encryption, &s.file),
+        }
+    }
+
+    pub fn to_sourcefile(
+        &self,
+        base: &Path,
+        context: &Context,
+        key: Option<&str>,
+    ) -> Result<Option<Resourcefile>> {
+        Ok(match self {
+            Secret::Opaque(s) => Some(s.to_sourcefile(base, key)?),
+            Secret::Regcred(s) => Some(s.to_sourcefile(base, key)?),
+            Secret::File(s) => Some(s.to_sourcefile(base, context, key)?),
+            Secret::Context(_) => None,
+        })
+    }
+
+    pub fn apply_context(
+        &self,
+        base: &Path,
+        context: &mut Context,
+        key: Option<&str>,
+    ) -> Result<()> {
+        match self {
+            Secret::Opaque(_) => (),
+            Secret::Regcred(_) => (),
+            Secret::File(_) => (),
+            Secret::Context(s) => s.apply_context(base, context, key)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// The longest key a ConfigMap or Secret may have.
+const MAX_KEY_LENGTH: usize = 253;
+
+/// The largest ConfigMap or Secret the API server accepts.
+const MAX_OBJECT_SIZE: usize = 1024 * 1024;
+
+/// Whether a file can go in the `data` of a ConfigMap, rather than base64 encoded in `binaryData`.
+fn is_text(content: &[u8]) -> bool {
+    !content.contains(&0) && std::str::from_utf8(content).is_ok()
+}
+
+/// A file of a hardmount: the key it is stored under and its path relative to the mount.
+#[derive(Debug, Clone)]
+struct HardmountEntry {
+    key: String,
+    path: String,
+    source: PathBuf,
+}
+
+/// Glob patterns for the files of a hardmount. Patterns without a `/` match the file name at any
+/// depth, others match the path relative to the source directory.
+struct Patterns {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Patterns {
+    fn new(patterns: &[String]) -> Result<Patterns> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            let glob = GlobBuilder::new(pattern.trim_start_matches('/'))
+                .literal_separator(true)
+                .build()
+                .map_err(|e| {
+                    Error::create(
+                        format!("Invalid pattern '{}': {}", pattern, e),
+                        ErrorKind::ManifestError,
+                    )
+                })?;
+            if pattern.contains('/') {
+                paths.add(glob);
+            } else {
+                names.add(glob);
+            }
+        }
+
+        Ok(Patterns {
+            names: names.build().context(ErrorKind::ManifestError)?,
+            paths: paths.build().context(ErrorKind::ManifestError)?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.paths.is_empty()
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        self.names.is_match(name) || self.paths.is_match(path)
+    }
+}
+
+/// Which files of a directory end up in a hardmount.
+struct Selection {
+    include: Patterns,
+    exclude: Patterns,
+}
+
+impl Selection {
+    /// Excluded directories are skipped entirely, includes only apply to files.
+    fn selects(&self, path: &str, is_dir: bool) -> bool {
+        !self.exclude.matches(path)
+            && (is_dir || self.include.is_empty() || self.include.matches(path))
+    }
+}
+
+/// Collect the selected files below `dir` in a stable order, descending into subdirectories.
+fn walk_dir(
+    dir: &Path,
+    prefix: &str,
+    selection: &Selection,
+    entries: &mut Vec<HardmountEntry>,
+) -> Result<()> {
+    let mut paths: Vec<PathBuf> = dir
+        .read_dir()
+        .context(ErrorKind::FileReadError {
+            name: dir.to_owned(),
+        })?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .collect();
+    paths.sort();
+
+    for source in paths {
+        let name = source.file_name().unwrap().to_string_lossy();
+        let path = format!("{}{}", prefix, name);
+
+        if !selection.selects(&path, source.is_dir()) {
+            continue;
+        }
+
+        if source.is_dir() {
+            walk_dir(&source, &format!("{}/", path), selection, entries)?;
+        } else if source.is_file() {
+            entries.push(HardmountEntry {
+                key: Hardmount::key(&path),
+                path,
+                source,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+impl Hardmount {
+    /// ConfigMap keys cannot contain slashes, so nested paths are flattened.
+    fn key(path: &str) -> String {
+        path.trim_matches('/').replace('/', "__")
+    }
+
+    /// The files of this hardmount including the remapped copies, ordered by path. Problems with
+    /// the layout are collected in `problems` rather than failing on the first one.
+    fn entries(&self, base: &Path, problems: &mut Vec<String>) -> Result<Vec<HardmountEntry>> {
+        let path = base.join(&self.src);
+
+        let mut entries = vec![];
+        if path.is_dir() {
+            let selection = Selection {
+                include: Patterns::new(&self.include)?,
+                exclude: Patterns::new(&self.exclude)?,
+            };
+            walk_dir(&path, "", &selection, &mut entries)?;
+        } else {
+            entries.push(HardmountEntry {
+                key: Hardmount::key(&path.file_name().unwrap().to_string_lossy()),
+                path: path.file_name().unwrap().to_string_lossy().to_string(),
+                source: path.clone(),
+            });
+        }
+
+        if let Some(remap) = &self.remap {
+            let mut remap: Vec<(&String, &String)> = remap.iter().collect();
+            remap.sort();
+
+            for (dst, src) in remap {
+                let source = match entries.iter().find(|e| &e.path == src || &e.key == src) {
+                    Some(entry) => entry.source.clone(),
+                    None => {
+                        problems.push(format!(
+                            "{:?}: remapped file '{}' does not exist",
+                            path, src
+                        ));
+                        continue;
+                    }
+                };
+
+                let dst = dst.trim_start_matches('/');
+                if dst.is_empty() || dst.split('/').any(|c| c.is_empty() || c == "..") {
+                    problems.push(format!(
+                        "{:?}: remap target '{}' is not a relative file path",
+                        source, dst
+                    ));
+                    continue;
+                }
+
+                entries.retain(|e| e.path != dst);
+                entries.push(HardmountEntry {
+                    key: Hardmount::key(dst),
+                    path: dst.to_owned(),
+                    source,
+                });
+            }
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut keys: HashMap<&str, &HardmountEntry> = HashMap::new();
+        for entry in &entries {
+            if let Some(other) = keys.insert(&entry.key, entry) {
+                problems.push(format!(
+                    "{:?}: '{}' and '{}' are both stored as key '{}'",
+                    entry.source, other.path, entry.path, entry.key
+                ));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Check the keys and size against the limits of the API server, which otherwise rejects the
+    /// object with an error that does not mention the file.
+    fn validate(
+        &self,
+        base: &Path,
+        entries: &[HardmountEntry],
+        content: &BTreeMap<String, Vec<u8>>,
+        problems: &mut Vec<String>,
+    ) {
+        for entry in entries {
+            if entry.key.len() > MAX_KEY_LENGTH {
+                problems.push(format!(
+                    "{:?}: key '{}' is longer than {} characters",
+                    entry.source, entry.key, MAX_KEY_LENGTH
+                ));
+            } else if entry.key == "." || entry.key.starts_with("..") {
+                problems.push(format!(
+                    "{:?}: key '{}' may not be '.' or start with '..'",
+                    entry.source, entry.key
+                ));
+            } else if let Some(c) = entry
+                .key
+                .chars()
+                .find(|c| !(c.is_ascii_alphanumeric() || "-._".contains(*c)))
+            {
+                problems.push(format!(
+                    "{:?}: key '{}' contains {:?}, only letters, digits, '-', '_' and '.' are allowed",
+                    entry.source, entry.key, c
+                ));
+            }
+        }
+
+        let size: usize = content.iter().map(|(k, v)| k.len() + v.len()).sum();
+        if size > MAX_OBJECT_SIZE {
+            let mut largest: Vec<(&String, usize)> =
+                content.iter().map(|(k, v)| (k, v.len())).collect();
+            largest.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+
+            problems.push(format!(
+                "{:?}: {} bytes exceeds the limit of 1 MiB, the largest files are {}",
+                base.join(&self.src),
+                size,
+                largest
+                    .iter()
+                    .take(3)
+                    .map(|(k, size)| format!("'{}' ({} bytes)", k, size))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ));
+        }
+    }
+
+    /// A short hash of the keys and content, which changes whenever a pod would see a change.
+    fn checksum(content: &BTreeMap<String, Vec<u8>>) -> String {
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        for (key, value) in content {
+            context.update(key.as_bytes());
+            context.update(&[0]);
+            context.update(&(value.len() as u64).to_be_bytes());
+            context.update(value);
+        }
+
+        context
+            .finish()
+            .as_ref()
+            .iter()
+            .take(5)
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn to_sourcefile(
+        &self,
+        base: &Path,
+        context: &Context,
+    ) -> Result<(Resourcefile, HardmountRef)> {
+        let read_f = |path: &Path| {
+            if self.template {
+                crate::util::process_template(path, context).map(|b| b.into_bytes())
+            } else {
+                std::fs::read(path).map_err(|_| {
+                    ErrorKind::FileReadError {
+                        name: path.to_path_buf(),
+                    }
+                    .into()
+                })
+            }
+        };
+
+        let mut problems = vec![];
+        let entries = self.entries(base, &mut problems)?;
+        let mut file_content: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+        for entry in &entries {
+            file_content.insert(entry.key.clone(), read_f(&entry.source)?);
+        }
+
+        self.validate(base, &entries, &file_content, &mut problems);
+        if self.binary == Some(false) && !self.secret {
+            for entry in &entries {
+                if !is_text(&file_content[&entry.key]) {
+                    problems.push(format!(
+                        "{:?}: not UTF-8 text, but binary is false",
+                        entry.source
+                    ));
+                }
+            }
+        }
+        if !problems.is_empty() {
+            return Err(Error::create(
+                problems.join("\n"),
+                ErrorKind::HardmountError {
+                    name: self.name.clone(),
+                    problems: problems.len(),
+                },
+            ));
+        }
+
+        let checksum = Hardmount::checksum(&file_content);
+        let reference = HardmountRef {
+            name: match self.hash {
+                Some(HardmountHash::Suffix) => format!("{}-{}", self.name, checksum),
+                _ => self.name.clone(),
+            },
+            checksum,
+            items: entries
+                .iter()
+                .map(|e| VolumeItem {
+                    key: e.key.clone(),
+                    path: e.path.clone(),
+                })
+                .collect(),
+        };
+
+        if self.secret {
+            let file = SecretOpaqueTemplate::instantiate(
+                &reference.name,
+                file_content.into_iter().collect(),
+            );
+            return Ok((file, reference));
+        }
+
+        let mut data = vec![];
+        let mut binary_data = vec![];
+        for entry in entries {
+            let content = file_content.remove(&entry.key).unwrap();
+            match String::from_utf8(content) {
+                Ok(text) if self.binary != Some(true) && is_text(text.as_bytes()) => {
+                    data.push((entry.key, text))
+                }
+                Ok(text) => binary_data.push((entry.key, text.into_bytes())),
+                Err(e) => binary_data.push((entry.key, e.into_bytes())),
+            }
+        }
+
+        let file = ConfigMapTemplate::instantiate(&reference.name, data, binary_data);
+
+        Ok((file, reference))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory with the given files, relative to it.
+    fn files(files: &[(&str, &[u8])]) -> tempfile::TempDir {
+        let directory = tempfile::tempdir().unwrap();
+        for (path, content) in files {
+            let path = directory.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        directory
+    }
+
+    fn hardmount(yaml: &str) -> Hardmount {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn hardmount_object(hardmount: &Hardmount, base: &Path) -> serde_json::Value {
+        let (file, _) = hardmount.to_sourcefile(base, &Context::new()).unwrap();
+        file.objects().unwrap().remove(0)
+    }
+
+    const CONFIG: &[u8] = b"server {\n    listen 80;\n}\n";
+    const BINARY: &[u8] = b"\x89PNG\r\n\x1a\n\x00\xff";
+
+    #[test]
+    fn hardmounts_put_text_in_data() {
+        let base = files(&[("conf/nginx.conf", CONFIG), ("conf/logo.png", BINARY)]);
+
+        let default = hardmount("{name: conf, src: conf, dest: /etc/nginx}");
+        let (file, _) = default.to_sourcefile(base.path(), &Context::new()).unwrap();
+        assert!(file
+            .buffer
+            .contains("\"nginx.conf\": |\n    server {\n        listen 80;\n    }\n"));
+
+        let object = hardmount_object(&default, base.path());
+        assert_eq!(
+            object["data"]["nginx.conf"],
+            "server {\n    listen 80;\n}\n"
+        );
+        assert_eq!(object["binaryData"]["logo.png"], base64::encode(BINARY));
+        assert!(object["data"].get("logo.png").is_none());
+
+        let binary = hardmount("{name: conf, src: conf, dest: /etc/nginx, binary: true}");
+        let object = hardmount_object(&binary, base.path());
+        assert_eq!(object["binaryData"]["nginx.conf"], base64::encode(CONFIG));
+        assert_eq!(object["binaryData"]["logo.png"], base64::encode(BINARY));
+        assert!(object.get("data").is_none());
+    }
+
+    #[test]
+    fn hardmounts_report_every_binary_file_when_binary_is_false() {
+        let base = files(&[
+            ("conf/nginx.conf", CONFIG),
+            ("conf/logo.png", BINARY),
+            ("conf/icon.png", BINARY),
+        ]);
+
+        let text = hardmount("{name: conf, src: conf, dest: /etc/nginx, binary: false}");
+        let error = text
+            .to_sourcefile(base.path(), &Context::new())
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(
+            error.kind(),
+            &ErrorKind::HardmountError {
+                name: "conf".to_owned(),
+                problems: 2
+            }
+        );
+        let message = error.message();
+        assert!(
+            message.contains("icon.png\": not UTF-8 text"),
+            "{}",
+            message
+        );
+        assert!(
+            message.contains("logo.png\": not UTF-8 text"),
+            "{}",
+            message
+        );
+
+        let base = files(&[("conf/nginx.conf", CONFIG)]);
+        let object = hardmount_object(&text, base.path());
+        assert_eq!(
+            object["data"]["nginx.conf"],
+            "server {\n    listen 80;\n}\n"
+        );
+    }
+
+    fn manifest(yaml: &str) -> Manifest {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn mounts_use_the_items_of_the_generated_hardmount() {
+        let base = files(&[
+            ("conf/nginx.conf", CONFIG),
+            ("conf/sites/default.conf", CONFIG),
+            ("conf/sites/default.conf.bak", CONFIG),
+        ]);
+        let manifest = manifest(
+            "version: 1\nnamespace: test\n\
+             hardmounts: [{name: conf, src: conf, dest: /etc/nginx, exclude: ['*.bak']}]\n",
+        );
+        let container: Container =
+            serde_yaml::from_str("{image: nginx, mounts: [{hardmount: conf}]}").unwrap();
+
+        let hardmount = &manifest.hardmounts.as_ref().unwrap()[0];
+        let (file, reference) = hardmount
+            .to_sourcefile(base.path(), &Context::new())
+            .unwrap();
+        let keys: Vec<String> = file.objects().unwrap()[0]["data"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+
+        let hardmounts = std::iter::once(("conf".to_owned(), reference)).collect();
+        let volumes = manifest.resolve_mounts(&container, &hardmounts).unwrap();
+        let items: Vec<(&str, &str)> = volumes[0]
+            .items
+            .iter()
+            .map(|item| (item.key.as_str(), item.path.as_str()))
+            .collect();
+
+        assert_eq!(
+            items,
+            vec![
+                ("nginx.conf", "nginx.conf"),
+                ("sites__default.conf", "sites/default.conf")
+            ]
+        );
+        assert_eq!(keys, vec!["nginx.conf", "sites__default.conf"]);
+
+        let error = manifest
+            .resolve_mounts(&container, &BTreeMap::new())
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ManifestError);
+    }
+
+    #[test]
+    fn routes_need_a_path_match_and_gateways_a_class_name() {
+        let ingress = |path_type: &str| -> Ingress {
+            serde_yaml::from_str(&format!(
+                "{{name: web, hosts: [example.com], service: web, port: 80, \
+                 certificate: web, paths: [{{path: /api, pathType: {}}}]}}",
+                path_type
+            ))
+            .unwrap()
+        };
+
+        let prefix = ingress("Prefix");
+        let groups = prefix.route_groups().unwrap();
+        let (route_match, backend) = &groups[0].1[0];
+        assert_eq!(route_match, &RouteMatch::PathPrefix);
+        assert_eq!(backend.path, "/api");
+
+        let error = ingress("ImplementationSpecific")
+            .route_groups()
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ManifestError);
+
+        let base = files(&[]);
+        let manifest = manifest(
+            "version: 1\nnamespace: test\ningressApi: gateway\n\
+             gateway: {name: web, className: ''}\n",
+        );
+        let error = manifest
+            .to_sourcefiles(base.path(), Context::new(), None)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ManifestError);
+    }
+
+    #[test]
+    fn volumes_use_the_storage_classes_in_options() {
+        let manifest = manifest("version: 1\nnamespace: test\noptions: [pd-ssd]\n");
+        let volume = |class: &str| -> VolumeClaim {
+            serde_yaml::from_str(&format!("{{name: data, size: 1Gi, {}}}", class)).unwrap()
+        };
+
+        assert_eq!(manifest.storage_class(&volume("")).unwrap(), Some("pd-ssd"));
+        assert_eq!(
+            manifest
+                .storage_class(&volume("storageClass: standard"))
+                .unwrap(),
+            Some("standard")
+        );
+        let error = manifest
+            .storage_class(&volume("storageClass: pd-hdd"))
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ManifestError);
+    }
+
+    #[test]
+    fn htpasswd_users_verify() {
+        let base = files(&[]);
+        let users = b"alice: correct horse\nbob: battery staple\n";
+        let encrypted = crate::secrets::encrypt_secret(
+            EncryptionType::AnsibleVault,
+            Path::new("users.yml"),
+            users,
+            Some("vault password"),
+            &[],
+        )
+        .unwrap();
+        std::fs::write(base.path().join("users.yml"), encrypted).unwrap();
+
+        let ingress: Ingress = serde_yaml::from_str(
+            "{name: web, hosts: [example.com], service: web, certificate: web, \
+             auth: {file: users.yml, encryption: ansible-vault}}",
+        )
+        .unwrap();
+        let secret = ingress
+            .auth_secret(base.path(), Some("vault password"))
+            .unwrap()
+            .unwrap();
+        let object = &secret.objects().unwrap()[0];
+        let htpasswd = base64::decode(object["data"]["auth"].as_str().unwrap()).unwrap();
+        let htpasswd = String::from_utf8(htpasswd).unwrap();
+
+        let lines: Vec<(&str, &str)> = htpasswd
+            .lines()
+            .map(|line| line.split_once(':').unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert!(bcrypt::verify("correct horse", lines[0].1).unwrap());
+        assert!(bcrypt::verify("battery staple", lines[1].1).unwrap());
+        assert!(!bcrypt::verify("battery staple", lines[0].1).unwrap());
+        assert!(lines[0].1.starts_with("$2y$10$"));
+
+        // The same users give the same Secret.
+        let again = ingress
+            .auth_secret(base.path(), Some("vault password"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(again.buffer, secret.buffer);
+    }
+
+    #[test]
+    fn issuers_default_to_the_kikundi_issuers() {
+        let name = |issuers: &Issuers, solver, staging| {
+            let issuer = issuers.issuer(solver, issuers.mode(staging)).unwrap();
+            (issuer.name, issuer.kind)
+        };
+
+        let issuers = Issuers::default();
+        assert_eq!(
+            name(&issuers, &CertificateSolver::HTTP, None),
+            (
+                "kikundi-production-http".to_owned(),
+                IssuerKind::ClusterIssuer
+            )
+        );
+        assert_eq!(
+            name(&issuers, &CertificateSolver::DNS, Some(true)),
+            ("kikundi-staging-dns".to_owned(), IssuerKind::ClusterIssuer)
+        );
+        assert!(issuers
+            .issuer(&CertificateSolver::NONE, IssuerMode::Production)
+            .is_none());
+
+        let issuers: Issuers =
+            serde_yaml::from_str("{staging: true, dns: {staging: letsencrypt-test, kind: Issuer}}")
+                .unwrap();
+        assert_eq!(
+            name(&issuers, &CertificateSolver::DNS, None),
+            ("letsencrypt-test".to_owned(), IssuerKind::Issuer)
+        );
+        assert_eq!(
+            name(&issuers, &CertificateSolver::DNS, Some(false)),
+            ("kikundi-production-dns".to_owned(), IssuerKind::Issuer)
+        );
+        assert_eq!(
+            name(&issuers, &CertificateSolver::HTTP, None),
+            ("kikundi-staging-http".to_owned(), IssuerKind::ClusterIssuer)
+        );
+    }
+
+    #[test]
+    fn wildcard_certificates_need_the_dns_solver() {
+        let certificate = |yaml: &str| -> Result<()> {
+            serde_yaml::from_str::<Certificate>(yaml)
+                .unwrap()
+                .validate()
+        };
+
+        let error = certificate("{name: wildcard, hosts: ['*.example.com']}").unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ManifestError);
+        assert!(error.message().contains("requires the DNS solver"));
+
+        certificate("{name: wildcard, hosts: ['*.example.com'], solver: DNS}").unwrap();
+        certificate("{name: web, hosts: [example.com]}").unwrap();
+        certificate("{name: web, hosts: [example.com], solver: NONE}").unwrap_err();
+        certificate("{name: web, hosts: []}").unwrap_err();
+    }
+
+    fn ingress(yaml: &str) -> Ingress {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn ingress_rules_group_the_hosts_by_their_paths() {
+        let ingress = ingress(
+            "{name: web, service: web, port: 80, certificate: web, \
+             paths: [{path: /}, {path: /api, pathType: Exact, service: api, port: 8080}], \
+             hosts: [example.com, www.example.com, \
+             {host: static.example.com, paths: [{port: 8000}]}]}",
+        );
+
+        let rules: Vec<_> = ingress
+            .rules()
+            .unwrap()
+            .into_iter()
+            .map(|(host, backends)| {
+                let backends = backends
+                    .iter()
+                    .map(|b| (b.path, b.path_type.as_str(), b.service, b.port))
+                    .collect::<Vec<_>>();
+                (host, backends)
+            })
+            .collect();
+        let paths = vec![("/", "Prefix", "web", 80), ("/api", "Exact", "api", 8080)];
+        assert_eq!(
+            rules,
+            vec![
+                ("example.com", paths.clone()),
+                ("www.example.com", paths),
+                ("static.example.com", vec![("/", "Prefix", "web", 8000)]),
+            ]
+        );
+
+        let groups: Vec<_> = ingress
+            .route_groups()
+            .unwrap()
+            .into_iter()
+            .map(|(hosts, backends)| {
+                let backends = backends
+                    .iter()
+                    .map(|(m, b)| (m.as_str(), b.path))
+                    .collect::<Vec<_>>();
+                (hosts, backends)
+            })
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                (
+                    vec!["example.com", "www.example.com"],
+                    vec![("PathPrefix", "/"), ("Exact", "/api")]
+                ),
+                (vec!["static.example.com"], vec![("PathPrefix", "/")]),
+            ]
+        );
+
+        let missing = self::ingress("{name: web, hosts: [example.com], certificate: web}");
+        assert!(missing.rules().is_err());
+    }
+
+    #[test]
+    fn controller_settings_translate_the_ingress_options() {
+        let ingress = ingress(
+            "{name: web, service: web, port: 80, certificate: web, hosts: [example.com], \
+             maxBodySize: 50m, sslRedirect: true, basicAuthSecret: users, \
+             annotations: {nginx.ingress.kubernetes.io/ssl-redirect: 'false'}}",
+        );
+
+        let (annotations, middlewares) = ingress
+            .controller_settings(IngressController::Nginx, "test")
+            .unwrap();
+        let annotation = |key: &str| annotations.get(key).map(String::as_str);
+        assert_eq!(
+            annotation("nginx.ingress.kubernetes.io/proxy-body-size"),
+            Some("50m")
+        );
+        assert_eq!(
+            annotation("nginx.ingress.kubernetes.io/ssl-redirect"),
+            Some("false")
+        );
+        assert_eq!(
+            annotation("nginx.ingress.kubernetes.io/auth-type"),
+            Some("basic")
+        );
+        assert_eq!(
+            annotation("nginx.ingress.kubernetes.io/auth-secret"),
+            Some("users")
+        );
+        assert_eq!(
+            annotation("nginx.ingress.kubernetes.io/auth-realm"),
+            Some(DEFAULT_REALM)
+        );
+        assert!(middlewares.is_empty());
+
+        let (annotations, middlewares) = ingress
+            .controller_settings(IngressController::Traefik, "test")
+            .unwrap();
+        assert_eq!(
+            annotations
+                .get("traefik.ingress.kubernetes.io/router.middlewares")
+                .map(String::as_str),
+            Some(
+                "test-web-body-size@kubernetescrd,test-web-redirect-https@kubernetescrd,\
+                 test-web-basic-auth@kubernetescrd"
+            )
+        );
+        assert!(annotations.contains_key("nginx.ingress.kubernetes.io/ssl-redirect"));
+        assert!(matches!(
+            middlewares[0].kind,
+            MiddlewareKind::BodySize(52_428_800)
+        ));
+        assert!(matches!(middlewares[1].kind, MiddlewareKind::RedirectHttps));
+        assert!(matches!(&middlewares[2].kind, MiddlewareKind::BasicAuth(s, _) if s == "users"));
+
+        let invalid = self::ingress(
+            "{name: web, service: web, port: 80, certificate: web, hosts: [example.com], \
+             maxBodySize: 50 megabytes}",
+        );
+        assert!(invalid
+            .controller_settings(IngressController::Nginx, "test")
+            .is_err());
+    }
+
+    #[test]
+    fn allow_from_merges_the_peers_of_a_namespace() {
+        let policy: NetworkPolicy = serde_yaml::from_str(
+            "allowFrom:\n\
+             - {namespace: monitoring, pods: {app: prometheus}, ports: [9090]}\n\
+             - {namespace: ingress}\n\
+             - {namespace: monitoring, pods: {app: grafana}}\n",
+        )
+        .unwrap();
+
+        let objects = NetworkPolicyRulesTemplate::instantiate(&policy)
+            .objects()
+            .unwrap();
+        let names: Vec<&str> = objects
+            .iter()
+            .map(|o| o["metadata"]["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["allow-from-monitoring", "allow-from-ingress"]);
+
+        let peer = |namespace: &str, pods: serde_json::Value| {
+            let mut peer = serde_json::json!({
+                "namespaceSelector": {
+                    "matchLabels": {"kubernetes.io/metadata.name": namespace}
+                }
+            });
+            if !pods.is_null() {
+                peer["podSelector"] = serde_json::json!({ "matchLabels": pods });
+            }
+            peer
+        };
+        assert_eq!(
+            objects[0]["spec"]["ingress"],
+            serde_json::json!([
+                {
+                    "from": [peer("monitoring", serde_json::json!({"app": "prometheus"}))],
+                    "ports": [{"protocol": "TCP", "port": 9090}],
+                },
+                {"from": [peer("monitoring", serde_json::json!({"app": "grafana"}))]},
+            ])
+        );
+        assert_eq!(
+            objects[1]["spec"]["ingress"],
+            serde_json::json!([{"from": [peer("ingress", serde_json::Value::Null)]}])
+        );
+    }
+
+    #[test]
+    fn egress_allows_the_configured_cluster_dns() {
+        let dns = |yaml: &str| {
+            let policy: NetworkPolicy = serde_yaml::from_str(yaml).unwrap();
+            let objects = NetworkPolicyRulesTemplate::instantiate(&policy)
+                .objects()
+                .unwrap();
+            objects[0]["spec"]["egress"][1]["to"][0].clone()
+        };
+
+        assert_eq!(
+            dns("egress: {}"),
+            serde_json::json!({
+                "namespaceSelector": {
+                    "matchLabels": {"kubernetes.io/metadata.name": "kube-system"}
+                },
+                "podSelector": {"matchLabels": {"k8s-app": "kube-dns"}},
+            })
+        );
+        assert_eq!(
+            dns("egress: {dnsNamespace: {name: dns}, dnsPods: {app: coredns, tier: dns}}"),
+            serde_json::json!({
+                "namespaceSelector": {"matchLabels": {"name": "dns"}},
+                "podSelector": {"matchLabels": {"app": "coredns", "tier": "dns"}},
+            })
+        );
+    }
+
+    #[test]
+    fn hardmount_checksums_are_stable() {
+        let content = |entries: &[(&str, &[u8])]| -> BTreeMap<String, Vec<u8>> {
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_vec()))
+                .collect()
+        };
+
+        let checksum =
+            Hardmount::checksum(&content(&[("nginx.conf", CONFIG), ("logo.png", BINARY)]));
+        assert_eq!(checksum, "8abaf31680");
+        assert_eq!(
+            Hardmount::checksum(&content(&[("logo.png", BINARY), ("nginx.conf", CONFIG)])),
+            checksum
+        );
+
+        // Renaming a file, or moving bytes between files, changes the checksum.
+        assert_ne!(
+            Hardmount::checksum(&content(&[("default.conf", CONFIG), ("logo.png", BINARY)])),
+            checksum
+        );
+        assert_ne!(
+            Hardmount::checksum(&content(&[("a", b"xy"), ("b", b"")])),
+            Hardmount::checksum(&content(&[("a", b"x"), ("b", b"y")]))
+        );
+    }
+
+    fn hardmount_problems(hardmount: &Hardmount, base: &Path) -> Vec<String> {
+        let error = hardmount
+            .to_sourcefile(base, &Context::new())
+            .map(|_| ())
+            .unwrap_err();
+        let message = error.as_fail().cause().unwrap().to_string();
+        message.lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn hardmounts_report_invalid_keys_and_sizes() {
+        let long = format!("conf/{}.conf", "a".repeat(250));
+        let large = vec![b'a'; MAX_OBJECT_SIZE];
+        let base = files(&[
+            ("conf/nginx.conf", CONFIG),
+            ("conf/my site.conf", CONFIG),
+            (&long, CONFIG),
+            ("conf/..hidden", CONFIG),
+            ("large/a.txt", &large),
+            ("large/b.txt", CONFIG),
+        ]);
+
+        let problems = hardmount_problems(
+            &hardmount("{name: conf, src: conf, dest: /etc/nginx}"),
+            base.path(),
+        );
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].ends_with("key '..hidden' may not be '.' or start with '..'"));
+        assert!(problems[1].contains("is longer than 253 characters"));
+        assert!(problems[2].ends_with(
+            "key 'my site.conf' contains ' ', only letters, digits, '-', '_' and '.' are allowed"
+        ));
+
+        let problems = hardmount_problems(
+            &hardmount("{name: large, src: large, dest: /srv}"),
+            base.path(),
+        );
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("exceeds the limit of 1 MiB"));
+        assert!(problems[0].ends_with(&format!(
+            "the largest files are 'a.txt' ({} bytes), 'b.txt' ({} bytes)",
+            MAX_OBJECT_SIZE,
+            CONFIG.len()
+        )));
+    }
+
+    #[test]
+    fn hardmounts_report_remap_collisions() {
+        let base = files(&[
+            ("conf/nginx.conf", CONFIG),
+            ("conf/sites/default.conf", CONFIG),
+            ("conf/sites__default.conf", CONFIG),
+        ]);
+
+        let problems = hardmount_problems(
+            &hardmount(
+                "{name: conf, src: conf, dest: /etc/nginx, \
+                 remap: {'sites/www.conf': nginx.conf, 'sites__www.conf': nginx.conf, \
+                 '../nginx.conf': nginx.conf, 'mime.types': missing.types}}",
+            ),
+            base.path(),
+        );
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].ends_with("remap target '../nginx.conf' is not a relative file path"));
+        assert!(problems[1].ends_with("remapped file 'missing.types' does not exist"));
+        assert!(problems[2].ends_with(
+            "'sites/default.conf' and 'sites__default.conf' are both stored as key \
+             'sites__default.conf'"
+        ));
+        assert!(problems[3].ends_with(
+            "'sites/www.conf' and 'sites__www.conf' are both stored as key 'sites__www.conf'"
+        ));
+
+        // Remapping onto an existing path replaces the file.
+        let remapped = hardmount(
+            "{name: conf, src: conf, dest: /etc/nginx, include: ['nginx.conf'], \
+             remap: {'default.conf': nginx.conf, 'nginx.conf': nginx.conf}}",
+        );
+        let object = hardmount_object(&remapped, base.path());
+        let keys: Vec<&String> = object["data"].as_object().unwrap().keys().collect();
+        assert_eq!(keys, vec!["default.conf", "nginx.conf"]);
+    }
+
+    #[test]
+    fn hardmounts_select_files_with_globs() {
+        let base = files(&[
+            ("conf/nginx.conf", CONFIG),
+            ("conf/mime.types", CONFIG),
+            ("conf/.hidden.conf", CONFIG),
+            ("conf/.git/config.conf", CONFIG),
+            ("conf/sites/default.conf", CONFIG),
+            ("conf/sites/default.conf.bak", CONFIG),
+            ("conf/sites/old/legacy.conf", CONFIG),
+            ("conf/snippets/ssl.conf", CONFIG),
+        ]);
+        let keys = |yaml: &str| -> Vec<String> {
+            let object = hardmount_object(&hardmount(yaml), base.path());
+            object["data"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect()
+        };
+
+        assert_eq!(
+            keys(
+                "{name: conf, src: conf, dest: /etc/nginx, include: ['*.conf'], \
+                 exclude: ['.*', sites/old, '/snippets']}"
+            ),
+            vec!["nginx.conf", "sites__default.conf"]
+        );
+        assert_eq!(
+            keys("{name: conf, src: conf, dest: /etc/nginx, include: ['sites/*.conf']}"),
+            vec!["sites__default.conf"]
+        );
+        assert_eq!(
+            keys("{name: conf, src: conf, dest: /etc/nginx, exclude: ['*.bak', '*/*/*']}").len(),
+            6
+        );
+
+        let invalid = hardmount("{name: conf, src: conf, dest: /etc/nginx, include: ['[']}");
+        let error = invalid
+            .to_sourcefile(base.path(), &Context::new())
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ManifestError);
     }
 }
@@ -1,4 +1,9 @@
+use crate::error::{Error, ErrorKind, Result};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, TScalarStyle, TokenType};
 
 pub struct Resourceproto<'a, T: 'a + askama::Template> {
     pub name: &'a str,
@@ -8,6 +13,8 @@ pub struct Resourceproto<'a, T: 'a + askama::Template> {
 pub struct Resourcefile {
     pub name: String,
     pub buffer: String,
+    /// Labels added to every object when it is applied, the buffer is written as it is.
+    pub labels: Vec<(String, String)>,
 }
 
 impl<'a, T: 'a + askama::Template> Resourceproto<'a, T> {
@@ -15,16 +22,17 @@ impl<'a, T: 'a + askama::Template> Resourceproto<'a, T> {
         Resourcefile {
             name: self.name.to_owned(),
             buffer: self.prototype.render().unwrap(),
+            labels: vec![],
         }
     }
 }
 
 impl Resourcefile {
-    fn ensure_base(path: &Path) -> Result<(), std::io::Error> {
+    fn ensure_base(path: &Path) -> std::result::Result<(), std::io::Error> {
         std::fs::create_dir_all(path)
     }
 
-    pub fn write(&self, mut path: PathBuf) -> Result<(), std::io::Error> {
+    pub fn write(&self, mut path: PathBuf) -> std::result::Result<(), std::io::Error> {
         path.push(&self.name);
         Resourcefile::ensure_base(path.parent().unwrap())?;
         println!("Writing to {}", &path.to_string_lossy());
@@ -37,4 +45,234 @@ impl Resourcefile {
             .push_str(&format!("\n\n# {}\n---\n", other.name));
         self.buffer.push_str(&other.buffer);
     }
+
+    /// Parse the Kubernetes objects in this file with their labels, skipping empty documents.
+    ///
+    /// Plain scalars are resolved like `kubectl` does, following YAML 1.1, such that e.g.
+    /// `defaultMode: 0644` is the octal number it is meant to be.
+    pub fn objects(&self) -> Result<Vec<Value>> {
+        let object_error = |message: String| {
+            Error::create(
+                message,
+                ErrorKind::ObjectError {
+                    name: self.name.clone(),
+                },
+            )
+        };
+
+        let mut builder = JsonBuilder::default();
+        Parser::new(self.buffer.chars())
+            .load(&mut builder, true)
+            .map_err(|e| object_error(e.to_string()))?;
+        if let Some(message) = builder.error {
+            return Err(object_error(message));
+        }
+
+        let mut objects = vec![];
+        for mut object in builder.documents {
+            if object.is_null() {
+                continue;
+            }
+
+            if !self.labels.is_empty() {
+                let metadata = object["metadata"]
+                    .as_object_mut()
+                    .ok_or_else(|| object_error("object has no metadata".to_owned()))?;
+                let existing = metadata
+                    .entry("labels")
+                    .or_insert_with(|| Value::Object(Default::default()));
+                if existing.is_null() {
+                    *existing = Value::Object(Default::default());
+                }
+                for (key, value) in &self.labels {
+                    existing[key] = Value::from(value.as_str());
+                }
+            }
+
+            objects.push(object);
+        }
+
+        Ok(objects)
+    }
+
+    /// Add labels to every object in this file when it is applied.
+    pub fn add_labels(&mut self, labels: &[(&str, &str)]) {
+        self.labels.extend(
+            labels
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+        );
+    }
+}
+
+enum Node {
+    Sequence(Vec<Value>, usize),
+    /// A mapping with the key of the value being parsed.
+    Mapping(Map<String, Value>, Option<String>, usize),
+}
+
+/// Builds JSON documents from the events of the YAML parser.
+#[derive(Default)]
+struct JsonBuilder {
+    documents: Vec<Value>,
+    stack: Vec<Node>,
+    anchors: HashMap<usize, Value>,
+    error: Option<String>,
+}
+
+impl JsonBuilder {
+    fn push(&mut self, value: Value, anchor: usize) {
+        if anchor > 0 {
+            self.anchors.insert(anchor, value.clone());
+        }
+
+        match self.stack.last_mut() {
+            None => self.documents.push(value),
+            Some(Node::Sequence(values, _)) => values.push(value),
+            Some(Node::Mapping(map, key, _)) => match key.take() {
+                Some(key) => {
+                    map.insert(key, value);
+                }
+                None => {
+                    *key = Some(match value {
+                        Value::String(s) => s,
+                        other => other.to_string(),
+                    })
+                }
+            },
+        }
+    }
+}
+
+impl MarkedEventReceiver for JsonBuilder {
+    fn on_event(&mut self, event: Event, _mark: Marker) {
+        match event {
+            Event::Scalar(value, style, anchor, tag) => {
+                let is_str = matches!(&tag, Some(TokenType::Tag(handle, suffix)) if handle == "!!" && suffix == "str");
+                let value = if style == TScalarStyle::Plain && !is_str {
+                    resolve_plain(&value)
+                } else {
+                    Value::String(value)
+                };
+                self.push(value, anchor);
+            }
+            Event::Alias(anchor) => match self.anchors.get(&anchor).cloned() {
+                Some(value) => self.push(value, 0),
+                None => self.error = Some(format!("unknown anchor {}", anchor)),
+            },
+            Event::SequenceStart(anchor) => self.stack.push(Node::Sequence(vec![], anchor)),
+            Event::MappingStart(anchor) => self.stack.push(Node::Mapping(Map::new(), None, anchor)),
+            Event::SequenceEnd | Event::MappingEnd => match self.stack.pop() {
+                Some(Node::Sequence(values, anchor)) => self.push(Value::Array(values), anchor),
+                Some(Node::Mapping(map, _, anchor)) => self.push(Value::Object(map), anchor),
+                None => (),
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Resolve an integer the way Go's `strconv.ParseInt` with base 0 does: with a `0x`, `0o`,
+/// `0b` or, for octal, just a `0` prefix.
+fn resolve_int(plain: &str) -> Option<i64> {
+    let (negative, digits) = match plain.as_bytes().first()? {
+        b'-' => (true, &plain[1..]),
+        b'+' => (false, &plain[1..]),
+        _ => (false, plain),
+    };
+
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x") | Some("0X") => (16, &digits[2..]),
+        Some("0o") | Some("0O") => (8, &digits[2..]),
+        Some("0b") | Some("0B") => (2, &digits[2..]),
+        _ if digits.len() > 1 && digits.starts_with('0') => (8, &digits[1..]),
+        _ => (10, digits),
+    };
+
+    // `from_str_radix` accepts a sign of its own.
+    if digits.is_empty() || digits.starts_with(['+', '-']) {
+        return None;
+    }
+
+    let value = i64::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+/// Resolve a plain scalar following YAML 1.1, like the YAML parser of `kubectl`.
+fn resolve_plain(plain: &str) -> Value {
+    match plain {
+        "" | "~" | "null" | "Null" | "NULL" => return Value::Null,
+        "y" | "Y" | "yes" | "Yes" | "YES" | "on" | "On" | "ON" | "true" | "True" | "TRUE" => {
+            return Value::Bool(true)
+        }
+        "n" | "N" | "no" | "No" | "NO" | "off" | "Off" | "OFF" | "false" | "False" | "FALSE" => {
+            return Value::Bool(false)
+        }
+        _ => (),
+    }
+
+    let number = plain.replace('_', "");
+    if let Some(int) = resolve_int(&number) {
+        return Value::from(int);
+    }
+
+    let is_float = number.bytes().any(|b| b.is_ascii_digit())
+        && number
+            .bytes()
+            .all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b));
+    match number.parse::<f64>() {
+        // Like Go, integral floats are written as integers.
+        Ok(float) if is_float && float.fract() == 0.0 && float.abs() < 1e15 => {
+            Value::from(float as i64)
+        }
+        Ok(float) if is_float => Value::from(float),
+        _ => Value::String(plain.to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(buffer: &str) -> Resourcefile {
+        Resourcefile {
+            name: "template.yml".to_owned(),
+            buffer: buffer.to_owned(),
+            labels: vec![],
+        }
+    }
+
+    #[test]
+    fn raw_template_keeps_octal_modes() {
+        let buffer = "apiVersion: v1\nkind: Pod\nmetadata:\n  name: test\nspec:\n  volumes:\n  - name: config\n    configMap:\n      name: config\n      defaultMode: 0644\n      items:\n      - key: a\n        path: a\n        mode: '0600'\n";
+        let mut file = template(buffer);
+        file.add_labels(&[("kuberwave/project", "test")]);
+
+        let objects = file.objects().unwrap();
+        let config_map = &objects[0]["spec"]["volumes"][0]["configMap"];
+        assert_eq!(config_map["defaultMode"], 0o644);
+        assert_eq!(config_map["items"][0]["mode"], "0600");
+        assert_eq!(
+            objects[0]["metadata"]["labels"]["kuberwave/project"],
+            "test"
+        );
+
+        // The template itself is written as it is.
+        assert_eq!(file.buffer, buffer);
+    }
+
+    #[test]
+    fn resolves_plain_scalars_like_kubectl() {
+        let file = template("---\na: 0o600\nb: 0x1F\nc: -12\nd: 1.5\ne: 2.0\nf: yes\ng: ~\nh: '12'\ni: !!str 12\nj: 1.2.3\nk: &k [1, 2]\nl: *k\n---\n");
+        let objects = file.objects().unwrap();
+
+        assert_eq!(objects.len(), 1);
+        assert_eq!(
+            objects[0],
+            serde_json::json!({
+                "a": 384, "b": 31, "c": -12, "d": 1.5, "e": 2, "f": true, "g": null,
+                "h": "12", "i": "12", "j": "1.2.3", "k": [1, 2], "l": [1, 2],
+            })
+        );
+    }
 }
@@ -0,0 +1,1091 @@
+//! Decryption of SOPS files with age keys, without the `sops` binary.
+//!
+//! Every value in a SOPS file is encrypted with AES-GCM using a data key, which is in turn
+//! encrypted to each of the age recipients in the `sops` metadata of the file.
+
+use crate::error::{Error, ErrorKind, Result};
+use aes_gcm::aead::consts::U32;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::aes::Aes256;
+use aes_gcm::{AesGcm, Nonce};
+use failure::ResultExt;
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// SOPS uses 32 byte nonces rather than the usual 12.
+type Cipher = AesGcm<Aes256, U32>;
+
+const AGE_KEY_PREFIX: &str = "AGE-SECRET-KEY-";
+
+/// Values under keys with this suffix are left unencrypted, the default of `sops`.
+const UNENCRYPTED_SUFFIX: &str = "_unencrypted";
+
+/// The version of the `sops` file format that is written.
+const SOPS_VERSION: &str = "3.9.0";
+
+/// The file formats SOPS supports, determined by the file extension like `sops` does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Yaml,
+    Json,
+    Dotenv,
+    /// Any other file is stored as a JSON document with the content under `data`.
+    Binary,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            Some("env") => Format::Dotenv,
+            _ => Format::Binary,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct AgeRecipient {
+    recipient: String,
+    enc: String,
+}
+
+/// The part of the `sops` metadata needed to decrypt with age.
+#[derive(Deserialize, Debug)]
+struct Metadata {
+    #[serde(default)]
+    age: Vec<AgeRecipient>,
+    lastmodified: String,
+    mac: String,
+    #[serde(default)]
+    mac_only_encrypted: Value,
+}
+
+impl Metadata {
+    fn mac_only_encrypted(&self) -> bool {
+        self.mac_only_encrypted == true || self.mac_only_encrypted == "true"
+    }
+}
+
+fn sops_error(file: &Path, message: String) -> Error {
+    Error::create(
+        message,
+        ErrorKind::SOPSError {
+            file: file.to_owned(),
+        },
+    )
+}
+
+/// Parse the age keys in `content`, one per line, skipping comments like in an age key file.
+fn parse_identities(content: &str, source: &str) -> Result<Vec<age::x25519::Identity>> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            age::x25519::Identity::from_str(line).map_err(|e| {
+                Error::create(
+                    e,
+                    ErrorKind::AgeKeyError {
+                        source: source.to_owned(),
+                    },
+                )
+            })
+        })
+        .collect()
+}
+
+/// The key file `sops` itself falls back to.
+fn default_key_file() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")))?;
+
+    Some(config.join("sops/age/keys.txt")).filter(|path| path.is_file())
+}
+
+/// Collect the age keys from `SOPS_AGE_KEY`, `SOPS_AGE_KEY_FILE`, the `SECRET` and the default
+/// key file of `sops`.
+pub fn identities(secret: Option<&str>) -> Result<Vec<age::x25519::Identity>> {
+    let mut identities = vec![];
+
+    if let Ok(keys) = std::env::var("SOPS_AGE_KEY") {
+        identities.extend(parse_identities(&keys, "SOPS_AGE_KEY")?);
+    }
+
+    let files = std::env::var_os("SOPS_AGE_KEY_FILE")
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(default_key_file());
+    for file in files {
+        let keys = std::fs::read_to_string(&file)
+            .context(ErrorKind::FileReadError { name: file.clone() })?;
+        identities.extend(parse_identities(&keys, &file.to_string_lossy())?);
+    }
+
+    // The SECRET may just as well be the password of an Ansible vault.
+    if let Some(secret) = secret {
+        let keys: Vec<&str> = secret
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with(AGE_KEY_PREFIX))
+            .collect();
+        identities.extend(parse_identities(&keys.join("\n"), "SECRET")?);
+    }
+
+    Ok(identities)
+}
+
+/// Decrypt the data key of the file with the first age key that is one of its recipients.
+fn data_key(
+    file: &Path,
+    metadata: &Metadata,
+    identities: &[age::x25519::Identity],
+) -> Result<Vec<u8>> {
+    if metadata.age.is_empty() {
+        return Err(sops_error(
+            file,
+            "The file has no age recipients, other key types are not supported".to_owned(),
+        ));
+    }
+    if identities.is_empty() {
+        return Err(sops_error(
+            file,
+            "No age key found, set SOPS_AGE_KEY, SOPS_AGE_KEY_FILE or SECRET".to_owned(),
+        ));
+    }
+
+    let public_keys: Vec<String> = identities
+        .iter()
+        .map(|i| i.to_public().to_string())
+        .collect();
+
+    for recipient in &metadata.age {
+        if !public_keys.contains(&recipient.recipient) {
+            continue;
+        }
+
+        let decryptor =
+            age::Decryptor::new(age::armor::ArmoredReader::new(recipient.enc.as_bytes()))
+                .map_err(|e| sops_error(file, format!("Invalid data key: {}", e)))?;
+        let mut reader = decryptor
+            .decrypt(identities.iter().map(|i| i as &dyn age::Identity))
+            .map_err(|e| {
+                sops_error(
+                    file,
+                    format!(
+                        "Could not decrypt the data key for {}: {}",
+                        recipient.recipient, e
+                    ),
+                )
+            })?;
+
+        let mut key = vec![];
+        reader
+            .read_to_end(&mut key)
+            .map_err(|e| sops_error(file, format!("Could not read the data key: {}", e)))?;
+        return Ok(key);
+    }
+
+    Err(sops_error(
+        file,
+        format!(
+            "None of the age keys ({}) is a recipient of the file, which is encrypted for {}",
+            public_keys.join(", "),
+            metadata
+                .age
+                .iter()
+                .map(|r| r.recipient.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ),
+    ))
+}
+
+/// An `ENC[AES256_GCM,data:...,iv:...,tag:...,type:...]` value.
+struct EncryptedValue {
+    data: Vec<u8>,
+    iv: Vec<u8>,
+    tag: Vec<u8>,
+    datatype: String,
+}
+
+impl EncryptedValue {
+    fn parse(value: &str) -> Option<EncryptedValue> {
+        let fields = value.strip_prefix("ENC[AES256_GCM,")?.strip_suffix(']')?;
+
+        let mut data = None;
+        let mut iv = None;
+        let mut tag = None;
+        let mut datatype = None;
+        for field in fields.split(',') {
+            match field.split_once(':')? {
+                ("data", value) => data = Some(base64::decode(value).ok()?),
+                ("iv", value) => iv = Some(base64::decode(value).ok()?),
+                ("tag", value) => tag = Some(base64::decode(value).ok()?),
+                ("type", value) => datatype = Some(value.to_owned()),
+                _ => (),
+            }
+        }
+
+        Some(EncryptedValue {
+            data: data?,
+            iv: iv?,
+            tag: tag?,
+            datatype: datatype?,
+        })
+    }
+
+    /// Decrypt the value, which is bound to the path of keys leading to it.
+    fn decrypt(&self, key: &[u8], path: &str) -> Option<Vec<u8>> {
+        if self.iv.len() != 32 {
+            return None;
+        }
+
+        let cipher = Cipher::new_from_slice(key).ok()?;
+        let mut message = self.data.clone();
+        message.extend_from_slice(&self.tag);
+
+        cipher
+            .decrypt(
+                Nonce::<U32>::from_slice(&self.iv),
+                Payload {
+                    msg: &message,
+                    aad: path.as_bytes(),
+                },
+            )
+            .ok()
+    }
+
+    /// Encrypt a value, binding it to the path of keys leading to it.
+    fn encrypt(key: &[u8], plaintext: &[u8], path: &str, datatype: &str) -> Result<String> {
+        let mut iv = [0; 32];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut iv)
+            .map_err(|_| Error::create("No randomness available", ErrorKind::Error))?;
+
+        let cipher = Cipher::new_from_slice(key)
+            .map_err(|_| Error::create("Invalid data key", ErrorKind::Error))?;
+        let mut data = cipher
+            .encrypt(
+                Nonce::<U32>::from_slice(&iv),
+                Payload {
+                    msg: plaintext,
+                    aad: path.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::create("Could not encrypt a value", ErrorKind::Error))?;
+        let tag = data.split_off(data.len() - 16);
+
+        Ok(format!(
+            "ENC[AES256_GCM,data:{},iv:{},tag:{},type:{}]",
+            base64::encode(data),
+            base64::encode(iv),
+            base64::encode(tag),
+            datatype
+        ))
+    }
+}
+
+/// The plaintext of a value and its type, as it is encrypted and hashed for the MAC of the file.
+/// Floats are formatted without exponent and booleans capitalized, like `sops` does.
+fn plaintext(value: &Value) -> Option<(Vec<u8>, &'static str)> {
+    match value {
+        Value::String(s) => Some((s.as_bytes().to_vec(), "str")),
+        Value::Bool(true) => Some((b"True".to_vec(), "bool")),
+        Value::Bool(false) => Some((b"False".to_vec(), "bool")),
+        Value::Number(n) if n.is_f64() => Some((format!("{}", n.as_f64()?).into_bytes(), "float")),
+        Value::Number(n) => Some((n.to_string().into_bytes(), "int")),
+        _ => None,
+    }
+}
+
+/// Call `f` for every value in the tree with the path of keys leading to it.
+fn walk_values(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    f: &mut dyn FnMut(&mut Value, &[String]) -> Result<()>,
+) -> Result<()> {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                path.push(key.clone());
+                walk_values(value, path, f)?;
+                path.pop();
+            }
+        }
+        // Items of a list share the path of the list.
+        Value::Array(values) => {
+            for value in values {
+                walk_values(value, path, f)?;
+            }
+        }
+        _ => f(value, path)?,
+    }
+
+    Ok(())
+}
+
+fn walk_tree(
+    tree: &mut Map<String, Value>,
+    f: &mut dyn FnMut(&mut Value, &[String]) -> Result<()>,
+) -> Result<()> {
+    for (key, value) in tree.iter_mut() {
+        walk_values(value, &mut vec![key.clone()], f)?;
+    }
+
+    Ok(())
+}
+
+/// The additional data binding a value to its path.
+fn additional_data(path: &[String]) -> String {
+    format!("{}:", path.join(":"))
+}
+
+fn hex_digest(mac: ring::digest::Context) -> String {
+    mac.finish()
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect()
+}
+
+/// Decrypt a value, returning the value with its original type and the plaintext.
+fn decrypt_value(
+    file: &Path,
+    key: &[u8],
+    value: &str,
+    path: &[String],
+) -> Result<Option<(Value, Vec<u8>)>> {
+    let encrypted = match EncryptedValue::parse(value) {
+        Some(encrypted) => encrypted,
+        None if value.starts_with("ENC[") => {
+            return Err(sops_error(
+                file,
+                format!("Invalid encrypted value at '{}'", path.join(".")),
+            ))
+        }
+        None => return Ok(None),
+    };
+
+    let plaintext = encrypted
+        .decrypt(key, &additional_data(path))
+        .ok_or_else(|| {
+            sops_error(
+                file,
+                format!("Could not decrypt the value at '{}'", path.join(".")),
+            )
+        })?;
+    let text = String::from_utf8(plaintext.clone()).map_err(|_| {
+        sops_error(
+            file,
+            format!(
+                "The value at '{}' is not UTF-8 text, which is only supported in binary files",
+                path.join(".")
+            ),
+        )
+    })?;
+
+    let value = match encrypted.datatype.as_str() {
+        "str" | "bytes" => Some(Value::String(text)),
+        "int" => text.parse::<i64>().ok().map(Value::from),
+        "float" => text.parse::<f64>().ok().map(Value::from),
+        "bool" => match text.to_lowercase().as_str() {
+            "true" | "t" | "1" => Some(Value::Bool(true)),
+            "false" | "f" | "0" => Some(Value::Bool(false)),
+            _ => None,
+        },
+        _ => None,
+    }
+    .ok_or_else(|| {
+        sops_error(
+            file,
+            format!(
+                "Unsupported value of type '{}' at '{}'",
+                encrypted.datatype,
+                path.join(".")
+            ),
+        )
+    })?;
+
+    Ok(Some((value, plaintext)))
+}
+
+/// Decrypt the tree of a document in place, checking it against the MAC in its metadata.
+fn decrypt_tree(
+    file: &Path,
+    tree: &mut Map<String, Value>,
+    metadata: &Metadata,
+    identities: &[age::x25519::Identity],
+) -> Result<()> {
+    let key = data_key(file, metadata, identities)?;
+    let mac_only_encrypted = metadata.mac_only_encrypted();
+
+    let mut mac = ring::digest::Context::new(&ring::digest::SHA512);
+    walk_tree(tree, &mut |value, path| {
+        let decrypted = match value {
+            Value::String(s) => decrypt_value(file, &key, s, path)?,
+            _ => None,
+        };
+
+        match decrypted {
+            Some((decrypted, plaintext)) => {
+                mac.update(&plaintext);
+                *value = decrypted;
+            }
+            None if !mac_only_encrypted => {
+                if let Some((plaintext, _)) = plaintext(value) {
+                    mac.update(&plaintext);
+                }
+            }
+            None => (),
+        }
+
+        Ok(())
+    })?;
+
+    verify_mac(file, &key, metadata, mac)
+}
+
+/// Check the digest of the plaintext values against the MAC in the metadata.
+fn verify_mac(
+    file: &Path,
+    key: &[u8],
+    metadata: &Metadata,
+    mac: ring::digest::Context,
+) -> Result<()> {
+    let mac = hex_digest(mac);
+    let expected = EncryptedValue::parse(&metadata.mac)
+        .and_then(|v| v.decrypt(key, &metadata.lastmodified))
+        .ok_or_else(|| sops_error(file, "Could not decrypt the MAC".to_owned()))?;
+
+    if !mac.eq_ignore_ascii_case(&String::from_utf8_lossy(&expected)) {
+        return Err(sops_error(
+            file,
+            "The MAC does not match, the file may have been tampered with".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Decrypt a binary file, which `sops` stores as a document with the raw content under `data`.
+fn decrypt_binary(
+    file: &Path,
+    tree: &Map<String, Value>,
+    metadata: &Metadata,
+    identities: &[age::x25519::Identity],
+) -> Result<Vec<u8>> {
+    let key = data_key(file, metadata, identities)?;
+
+    let content = match tree.get("data").and_then(Value::as_str) {
+        Some("") => vec![],
+        Some(data) => EncryptedValue::parse(data)
+            .and_then(|data| data.decrypt(&key, &additional_data(&["data".to_owned()])))
+            .ok_or_else(|| sops_error(file, "Could not decrypt the data".to_owned()))?,
+        None => return Err(sops_error(file, "The file has no data".to_owned())),
+    };
+
+    let mut mac = ring::digest::Context::new(&ring::digest::SHA512);
+    mac.update(&content);
+    verify_mac(file, &key, metadata, mac)?;
+
+    Ok(content)
+}
+
+/// Encrypt the data key to an age recipient, armored like `sops` stores it.
+fn encrypt_data_key(file: &Path, key: &[u8], recipient: &age::x25519::Recipient) -> Result<String> {
+    let io_error = || ErrorKind::SOPSError {
+        file: file.to_owned(),
+    };
+
+    let encryptor =
+        age::Encryptor::with_recipients(std::iter::once(recipient as &dyn age::Recipient))
+            .map_err(|e| sops_error(file, format!("Could not encrypt the data key: {}", e)))?;
+
+    let mut armored = vec![];
+    let output =
+        age::armor::ArmoredWriter::wrap_output(&mut armored, age::armor::Format::AsciiArmor)
+            .context(io_error())?;
+    let mut writer = encryptor.wrap_output(output).context(io_error())?;
+    writer.write_all(key).context(io_error())?;
+    writer
+        .finish()
+        .and_then(|output| output.finish())
+        .context(io_error())?;
+
+    Ok(String::from_utf8(armored).context(io_error())?)
+}
+
+/// The current time as `sops` writes `lastmodified`, in RFC 3339 format.
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+fn new_data_key() -> Result<[u8; 32]> {
+    let mut key = [0; 32];
+    ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut key)
+        .map_err(|_| Error::create("No randomness available", ErrorKind::Error))?;
+
+    Ok(key)
+}
+
+/// The metadata to store with a document encrypted with `key`, with the data key encrypted for
+/// the age `recipients` and the digest of the plaintext values as MAC.
+fn seal(
+    file: &Path,
+    key: &[u8],
+    mac: ring::digest::Context,
+    recipients: &[String],
+) -> Result<Value> {
+    if recipients.is_empty() {
+        return Err(sops_error(
+            file,
+            "No age recipients to encrypt for".to_owned(),
+        ));
+    }
+    let recipients = recipients
+        .iter()
+        .map(|r| {
+            age::x25519::Recipient::from_str(r)
+                .map_err(|e| sops_error(file, format!("Invalid age recipient '{}': {}", r, e)))
+        })
+        .collect::<Result<Vec<age::x25519::Recipient>>>()?;
+
+    let lastmodified = now();
+    let age = recipients
+        .iter()
+        .map(|recipient| {
+            Ok(serde_json::json!({
+                "recipient": recipient.to_string(),
+                "enc": encrypt_data_key(file, key, recipient)?,
+            }))
+        })
+        .collect::<Result<Vec<Value>>>()?;
+
+    Ok(serde_json::json!({
+        "age": age,
+        "lastmodified": lastmodified,
+        "mac": EncryptedValue::encrypt(key, hex_digest(mac).as_bytes(), &lastmodified, "str")?,
+        "unencrypted_suffix": UNENCRYPTED_SUFFIX,
+        "version": SOPS_VERSION,
+    }))
+}
+
+/// Encrypt the tree of a document in place with a new data key for the age `recipients`,
+/// returning the metadata to store with it.
+fn encrypt_tree(
+    file: &Path,
+    tree: &mut Map<String, Value>,
+    recipients: &[String],
+) -> Result<Value> {
+    let key = new_data_key()?;
+
+    let mut mac = ring::digest::Context::new(&ring::digest::SHA512);
+    walk_tree(tree, &mut |value, path| {
+        if let Some((plaintext, datatype)) = plaintext(value) {
+            mac.update(&plaintext);
+
+            // Empty values are left as they are, like `sops` does.
+            if !plaintext.is_empty() && !path.iter().any(|p| p.ends_with(UNENCRYPTED_SUFFIX)) {
+                *value = Value::from(EncryptedValue::encrypt(
+                    &key,
+                    &plaintext,
+                    &additional_data(path),
+                    datatype,
+                )?);
+            }
+        }
+
+        Ok(())
+    })?;
+
+    seal(file, &key, mac, recipients)
+}
+
+/// Encrypt the content of a binary file as the `data` of a document, which may be any bytes.
+fn encrypt_binary(file: &Path, content: &[u8], recipients: &[String]) -> Result<Vec<u8>> {
+    let key = new_data_key()?;
+
+    let mut mac = ring::digest::Context::new(&ring::digest::SHA512);
+    mac.update(content);
+
+    let data = match content {
+        [] => String::new(),
+        content => {
+            EncryptedValue::encrypt(&key, content, &additional_data(&["data".to_owned()]), "str")?
+        }
+    };
+    let tree = std::iter::once(("data".to_owned(), Value::from(data))).collect();
+
+    render(
+        file,
+        Format::Binary,
+        tree,
+        Some(seal(file, &key, mac, recipients)?),
+    )
+}
+
+/// Split the `sops` metadata off a document.
+fn take_metadata(file: &Path, tree: &mut Map<String, Value>) -> Result<Metadata> {
+    let metadata = tree
+        .remove("sops")
+        .ok_or_else(|| sops_error(file, "The file is not encrypted with SOPS".to_owned()))?;
+
+    parse_metadata(file, metadata)
+}
+
+fn parse_metadata(file: &Path, metadata: Value) -> Result<Metadata> {
+    Ok(
+        serde_json::from_value(metadata).context(ErrorKind::SOPSError {
+            file: file.to_owned(),
+        })?,
+    )
+}
+
+/// Dotenv files have the metadata flattened into `sops_` prefixed variables, with `__` between
+/// the parts of a path, like `sops_age__list_0__map_recipient`.
+fn unflatten_metadata(variables: &mut Map<String, Value>) -> Value {
+    let names: Vec<String> = variables
+        .keys()
+        .filter(|name| name.starts_with("sops_"))
+        .cloned()
+        .collect();
+
+    let mut metadata = Value::Object(Map::new());
+    for name in names {
+        let value = variables.remove(&name).unwrap();
+
+        let mut target = &mut metadata;
+        for part in name["sops_".len()..].split("__") {
+            target = match part.strip_prefix("list_").map(str::parse::<usize>) {
+                Some(Ok(index)) => {
+                    if !target.is_array() {
+                        *target = Value::Array(vec![]);
+                    }
+                    let items = target.as_array_mut().unwrap();
+                    if items.len() <= index {
+                        items.resize(index + 1, Value::Null);
+                    }
+                    &mut items[index]
+                }
+                _ => {
+                    if !target.is_object() {
+                        *target = Value::Object(Map::new());
+                    }
+                    let key = part.strip_prefix("map_").unwrap_or(part);
+                    target
+                        .as_object_mut()
+                        .unwrap()
+                        .entry(key)
+                        .or_insert(Value::Null)
+                }
+            };
+        }
+        *target = value;
+    }
+
+    metadata
+}
+
+fn parse_dotenv(content: &str) -> Map<String, Value> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.to_owned(), Value::from(value.replace("\\n", "\n"))))
+        .collect()
+}
+
+/// The inverse of `unflatten_metadata`.
+fn flatten_metadata(name: String, value: &Value, variables: &mut Map<String, Value>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                flatten_metadata(format!("{}__map_{}", name, key), value, variables);
+            }
+        }
+        Value::Array(values) => {
+            for (index, value) in values.iter().enumerate() {
+                flatten_metadata(format!("{}__list_{}", name, index), value, variables);
+            }
+        }
+        value => {
+            variables.insert(name, value.clone());
+        }
+    }
+}
+
+fn to_dotenv(variables: &Map<String, Value>) -> String {
+    variables
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            format!("{}={}\n", name, value.replace('\n', "\\n"))
+        })
+        .collect()
+}
+
+fn parse(file: &Path, format: Format, content: &[u8]) -> Result<Map<String, Value>> {
+    let parse_error = || ErrorKind::ParseError {
+        name: file.to_owned(),
+    };
+
+    Ok(match format {
+        Format::Yaml => serde_yaml::from_slice(content).context(parse_error())?,
+        // Encrypted binary files are JSON documents, see `encrypt_binary`.
+        Format::Json | Format::Binary => serde_json::from_slice(content).context(parse_error())?,
+        Format::Dotenv => parse_dotenv(std::str::from_utf8(content).context(parse_error())?),
+    })
+}
+
+/// Render a document in its format, as an encrypted file if the metadata is given. Binary files
+/// are only rendered encrypted, their plaintext is the content itself.
+fn render(
+    file: &Path,
+    format: Format,
+    mut tree: Map<String, Value>,
+    metadata: Option<Value>,
+) -> Result<Vec<u8>> {
+    let parse_error = || ErrorKind::ParseError {
+        name: file.to_owned(),
+    };
+
+    if let Some(metadata) = metadata {
+        match format {
+            Format::Dotenv => {
+                for (key, value) in metadata.as_object().into_iter().flatten() {
+                    flatten_metadata(format!("sops_{}", key), value, &mut tree);
+                }
+            }
+            _ => {
+                tree.insert("sops".to_owned(), metadata);
+            }
+        }
+    }
+
+    Ok(match format {
+        Format::Yaml => serde_yaml::to_string(&tree)
+            .context(parse_error())?
+            .into_bytes(),
+        Format::Json | Format::Binary => {
+            let mut json = serde_json::to_vec_pretty(&tree).context(parse_error())?;
+            json.push(b'\n');
+            json
+        }
+        Format::Dotenv => to_dotenv(&tree).into_bytes(),
+    })
+}
+
+fn has_metadata(tree: &Map<String, Value>) -> bool {
+    tree.contains_key("sops") || tree.keys().any(|key| key.starts_with("sops_"))
+}
+
+/// Whether `content` is a document encrypted with SOPS, in the format of `file`.
+pub fn is_encrypted(file: &Path, content: &[u8]) -> bool {
+    parse(file, Format::from_path(file), content)
+        .ok()
+        .as_ref()
+        .is_some_and(has_metadata)
+}
+
+/// Read an encrypted file, splitting off its metadata.
+fn load(file: &Path) -> Result<(Format, Map<String, Value>, Metadata)> {
+    let content = std::fs::read(file).context(ErrorKind::FileReadError {
+        name: file.to_owned(),
+    })?;
+
+    let format = Format::from_path(file);
+    let mut tree = parse(file, format, &content)?;
+
+    let metadata = match format {
+        Format::Dotenv => parse_metadata(file, unflatten_metadata(&mut tree))?,
+        _ => take_metadata(file, &mut tree)?,
+    };
+
+    Ok((format, tree, metadata))
+}
+
+fn decrypt(file: &Path, identities: &[age::x25519::Identity]) -> Result<Vec<u8>> {
+    let (format, mut tree, metadata) = load(file)?;
+    if format == Format::Binary {
+        return decrypt_binary(file, &tree, &metadata, identities);
+    }
+
+    decrypt_tree(file, &mut tree, &metadata, identities)?;
+
+    render(file, format, tree, None)
+}
+
+/// Decrypt a SOPS encrypted file, returning the content in its original format.
+pub fn read_from_file(file: &Path, secret: Option<&str>) -> Result<Vec<u8>> {
+    decrypt(file, &identities(secret)?)
+}
+
+/// The age recipients a SOPS file is encrypted for.
+pub fn recipients(file: &Path) -> Result<Vec<String>> {
+    let (_, _, metadata) = load(file)?;
+
+    Ok(metadata.age.into_iter().map(|r| r.recipient).collect())
+}
+
+/// Encrypt `content`, in the format of `file`, for the age `recipients` with a new data key.
+/// Comments in the content are not preserved.
+pub fn encrypt(file: &Path, content: &[u8], recipients: &[String]) -> Result<Vec<u8>> {
+    if is_encrypted(file, content) {
+        return Err(sops_error(
+            file,
+            "The content is already encrypted with SOPS".to_owned(),
+        ));
+    }
+
+    let format = Format::from_path(file);
+    if format == Format::Binary {
+        return encrypt_binary(file, content, recipients);
+    }
+    let mut tree = parse(file, format, content)?;
+
+    let metadata = encrypt_tree(file, &mut tree, recipients)?;
+
+    render(file, format, tree, Some(metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: [&str; 4] = ["secret.yaml", "secret.json", "secret.env", "secret.crt"];
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/sops")
+            .join(name)
+    }
+
+    fn test_identities() -> Vec<age::x25519::Identity> {
+        let keys = std::fs::read_to_string(fixture("key.txt")).unwrap();
+        parse_identities(&keys, "key.txt").unwrap()
+    }
+
+    /// The document in `content`, to compare files regardless of how they are formatted.
+    fn document(file: &Path, content: &[u8]) -> Map<String, Value> {
+        parse(file, Format::from_path(file), content).unwrap()
+    }
+
+    fn test_recipient() -> String {
+        test_identities()[0].to_public().to_string()
+    }
+
+    /// Encrypt the plaintext of a fixture to a file with the same name in `directory`.
+    fn encrypt_fixture(directory: &Path, name: &str) -> PathBuf {
+        let plain = std::fs::read(fixture(&format!("plain/{}", name))).unwrap();
+        let file = directory.join(name);
+        let encrypted = encrypt(&file, &plain, &[test_recipient()]).unwrap();
+        std::fs::write(&file, encrypted).unwrap();
+
+        file
+    }
+
+    /// The encrypted document in `file` and its `sops` metadata.
+    fn encrypted_document(file: &Path) -> (Map<String, Value>, Value) {
+        let format = Format::from_path(file);
+        let content = std::fs::read(file).unwrap();
+        let mut tree = parse(file, format, &content).unwrap();
+        let metadata = match format {
+            Format::Dotenv => unflatten_metadata(&mut tree),
+            _ => tree.remove("sops").unwrap(),
+        };
+
+        (tree, metadata)
+    }
+
+    /// A document with the encrypted values replaced by their type.
+    fn shape(value: &Value) -> Value {
+        match value {
+            Value::Object(map) => map.iter().map(|(k, v)| (k.clone(), shape(v))).collect(),
+            Value::Array(values) => values.iter().map(shape).collect(),
+            Value::String(s) => match EncryptedValue::parse(s) {
+                Some(encrypted) => Value::from(format!("ENC[{}]", encrypted.datatype)),
+                None => value.clone(),
+            },
+            _ => value.clone(),
+        }
+    }
+
+    /// The names of the fields of the metadata and of its age recipients.
+    fn metadata_fields(metadata: &Value) -> Vec<String> {
+        let recipients = metadata["age"].as_array().unwrap().iter();
+        let fields = recipients
+            .flat_map(|recipient| recipient.as_object().unwrap().keys())
+            .map(|field| format!("age.{}", field));
+
+        metadata
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .chain(fields)
+            .collect()
+    }
+
+    #[test]
+    fn decrypts_fixtures() {
+        for name in &FORMATS {
+            let file = fixture(name);
+            let decrypted = decrypt(&file, &test_identities()).unwrap();
+            let plain = std::fs::read(fixture(&format!("plain/{}", name))).unwrap();
+
+            if Format::from_path(&file) == Format::Binary {
+                assert_eq!(decrypted, plain, "{}", name);
+            } else {
+                assert_eq!(
+                    document(&file, &decrypted),
+                    document(&file, &plain),
+                    "{}",
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_fixtures() {
+        let directory = tempfile::tempdir().unwrap();
+
+        // Unencrypted values are only protected by the MAC.
+        let file = directory.path().join("secret.yaml");
+        let content = std::fs::read_to_string(fixture("secret.yaml")).unwrap();
+        let tampered = content.replace("left as it is", "changed");
+        assert_ne!(content, tampered);
+        std::fs::write(&file, tampered).unwrap();
+
+        let error = decrypt(&file, &test_identities()).unwrap_err();
+        assert!(format!("{:?}", error).contains("The MAC does not match"));
+
+        // Dropping a value changes the MAC just as well.
+        let file = directory.path().join("secret.env");
+        let content = std::fs::read_to_string(fixture("secret.env")).unwrap();
+        let tampered: String = content
+            .lines()
+            .filter(|line| !line.starts_with("API_TOKEN="))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        std::fs::write(&file, tampered).unwrap();
+
+        let error = decrypt(&file, &test_identities()).unwrap_err();
+        assert!(format!("{:?}", error).contains("The MAC does not match"));
+    }
+
+    #[test]
+    fn requires_a_recipient_key() {
+        let other = age::x25519::Identity::generate();
+        let error = decrypt(&fixture("secret.json"), &[other]).unwrap_err();
+        assert!(format!("{:?}", error).contains("is a recipient of the file"));
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypts() {
+        let directory = tempfile::tempdir().unwrap();
+
+        for name in &FORMATS {
+            let file = encrypt_fixture(directory.path(), name);
+            assert!(is_encrypted(&file, &std::fs::read(&file).unwrap()));
+            assert_eq!(recipients(&file).unwrap(), vec![test_recipient()]);
+
+            let decrypted = decrypt(&file, &test_identities()).unwrap();
+            let plain = std::fs::read(fixture(&format!("plain/{}", name))).unwrap();
+            if Format::from_path(&file) == Format::Binary {
+                assert_eq!(decrypted, plain, "{}", name);
+            } else {
+                assert_eq!(
+                    document(&file, &decrypted),
+                    document(&file, &plain),
+                    "{}",
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn encrypts_like_the_fixtures() {
+        let directory = tempfile::tempdir().unwrap();
+
+        for name in &FORMATS {
+            let (tree, metadata) = encrypted_document(&encrypt_fixture(directory.path(), name));
+            let (expected_tree, expected_metadata) = encrypted_document(&fixture(name));
+
+            // The same values are encrypted, with the same types, and the rest is left as is.
+            assert_eq!(
+                shape(&Value::Object(tree)),
+                shape(&Value::Object(expected_tree)),
+                "{}",
+                name
+            );
+            assert_eq!(
+                metadata_fields(&metadata),
+                metadata_fields(&expected_metadata),
+                "{}",
+                name
+            );
+            assert_eq!(metadata["version"], expected_metadata["version"]);
+            assert_eq!(
+                metadata["unencrypted_suffix"],
+                expected_metadata["unencrypted_suffix"]
+            );
+        }
+    }
+
+    #[test]
+    fn writes_lastmodified_like_sops() {
+        let lastmodified = now();
+
+        // Go formats UTC as `2006-01-02T15:04:05Z`, without fractional seconds.
+        assert_eq!(lastmodified.len(), "2006-01-02T15:04:05Z".len());
+        assert!(lastmodified.ends_with('Z'));
+        assert!(chrono::DateTime::parse_from_rfc3339(&lastmodified).is_ok());
+    }
+
+    #[test]
+    fn keeps_binary_files_as_they_are() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("logo.png");
+        let content = b"\x89PNG\r\n\x1a\n\x00\xff\xfe";
+
+        std::fs::write(&file, encrypt(&file, content, &[test_recipient()]).unwrap()).unwrap();
+        assert_eq!(decrypt(&file, &test_identities()).unwrap(), content);
+    }
+
+    #[test]
+    fn rejects_values_that_are_not_text() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("secret.yaml");
+
+        let key = new_data_key().unwrap();
+        let plaintext = b"\xff\xfe";
+        let mut mac = ring::digest::Context::new(&ring::digest::SHA512);
+        mac.update(plaintext);
+
+        let mut tree = Map::new();
+        let password = EncryptedValue::encrypt(&key, plaintext, "password:", "str").unwrap();
+        tree.insert("password".to_owned(), Value::from(password));
+        let metadata = seal(&file, &key, mac, &[test_recipient()]).unwrap();
+        std::fs::write(
+            &file,
+            render(&file, Format::Yaml, tree, Some(metadata)).unwrap(),
+        )
+        .unwrap();
+
+        let error = decrypt(&file, &test_identities()).unwrap_err();
+        assert!(
+            error.message().contains("'password' is not UTF-8 text"),
+            "{}",
+            error.message()
+        );
+    }
+}
@@ -0,0 +1,9 @@
+#!/bin/sh
+# Encrypt the files in plain/ with `sops` for the test key in key.txt.
+set -e
+cd "$(dirname "$0")"
+
+recipient=$(sed -n 's/^# public key: //p' key.txt)
+for file in secret.yaml secret.json secret.env secret.crt; do
+    SOPS_AGE_KEY_FILE=key.txt sops --encrypt --age "$recipient" "plain/$file" > "$file"
+done